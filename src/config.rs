@@ -1,7 +1,9 @@
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 
-use chrono::{DateTime, Datelike, Local};
-use log::debug;
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Datelike, Local, NaiveDate, Weekday};
+use log::{debug, warn};
 use platform_dirs::AppDirs;
 use serde::Deserialize;
 
@@ -15,21 +17,70 @@ pub struct WindowConfig {
     pub full_screen: bool,
 }
 
-#[derive(Debug, Default, Clone, Deserialize)]
+/// The service the weather comes from
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WeatherProviderKind {
+    #[default]
+    #[serde(rename = "qweather")]
+    QWeather,
+    OpenMeteo,
+}
+
+#[derive(Debug, Clone, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct WeatherConfig {
-    #[serde(default = "default_api_host")]
-    pub api_host: String,
+    #[serde(default)]
+    pub provider: WeatherProviderKind,
+    /// Use the default host of the provider if not set
+    pub api_host: Option<String>,
+    /// QWeather location ID
+    #[serde(default)]
     pub location: String,
+    #[serde(default)]
     pub app_id: String,
+    #[serde(default)]
     pub key_id: String,
+    #[serde(default)]
     pub signing_key: String,
+    /// Coordinates used by Open-Meteo
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    /// Number of days in the forecast row, including today, 0 to hide it
+    #[serde(default = "default_forecast_days")]
+    pub forecast_days: usize,
+    /// Number of hours in the hourly chart, rounded down to a multiple of 3, 0 to hide it
+    #[serde(default = "default_hourly_hours")]
+    pub hourly_hours: usize,
+    /// Show the air quality next to the weather, only QWeather has it
+    #[serde(default)]
+    pub air_quality: bool,
 }
 
-fn default_api_host() -> String {
-    option_env!("WEATHER_API_HOST")
-        .unwrap_or("devapi.qweather.com")
-        .to_string()
+impl Default for WeatherConfig {
+    fn default() -> Self {
+        Self {
+            provider: WeatherProviderKind::default(),
+            api_host: None,
+            location: String::new(),
+            app_id: String::new(),
+            key_id: String::new(),
+            signing_key: String::new(),
+            latitude: None,
+            longitude: None,
+            forecast_days: default_forecast_days(),
+            hourly_hours: default_hourly_hours(),
+            air_quality: false,
+        }
+    }
+}
+
+fn default_forecast_days() -> usize {
+    3
+}
+
+fn default_hourly_hours() -> usize {
+    12
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -37,16 +88,202 @@ fn default_api_host() -> String {
 pub struct TodoConfig {
     #[serde(default = "default_app_id")]
     pub app_id: String,
+    /// Azure AD tenant, `consumers`, `organizations`, `common` or a tenant ID or domain
+    #[serde(default = "default_tenant")]
+    pub tenant: String,
+    /// The token caches are encrypted with a key derived from this passphrase
+    pub token_passphrase: Option<String>,
+    /// Or from the content of this file, the machine id is used if neither is set
+    pub token_key_file: Option<PathBuf>,
+    /// Base URL of the Microsoft identity platform, differs in national clouds
+    #[serde(default = "default_login_url")]
+    pub login_url: String,
+    /// Base URL of Microsoft Graph, including the API version
+    #[serde(default = "default_graph_url")]
+    pub graph_url: String,
+    #[serde(default)]
+    pub sources: Vec<CalendarSourceConfig>,
+    /// IANA time zone the Outlook events are requested in, e.g. `Asia/Shanghai`, the system
+    /// time zone if not set. The events are still shown in the system time zone
+    pub time_zone: Option<String>,
+    /// Number of days shown, including today
+    #[serde(default = "default_lookahead_days")]
+    pub lookahead_days: u64,
+    /// Whether to show the events of today that have already ended
+    #[serde(default = "default_include_past_events")]
+    pub include_past_events: bool,
+    /// `%A` is the weekday name in `[window] weekdays`, `%R` is the name in `relative-day-names`
+    #[serde(default = "default_group_name_format")]
+    pub group_name_format: String,
+    /// Names of today, tomorrow, etc., used by `%R` in `group-name-format`
+    #[serde(default)]
+    pub relative_day_names: Vec<String>,
+    /// `#RRGGBB` colors of the categories, used when the source doesn't provide one
+    #[serde(default)]
+    pub category_colors: HashMap<String, String>,
 }
 
 impl Default for TodoConfig {
     fn default() -> Self {
         Self {
             app_id: default_app_id(),
+            tenant: default_tenant(),
+            token_passphrase: None,
+            token_key_file: None,
+            login_url: default_login_url(),
+            graph_url: default_graph_url(),
+            sources: vec![],
+            time_zone: None,
+            lookahead_days: default_lookahead_days(),
+            include_past_events: default_include_past_events(),
+            group_name_format: default_group_name_format(),
+            relative_day_names: vec![],
+            category_colors: HashMap::new(),
+        }
+    }
+}
+
+fn default_tenant() -> String {
+    "consumers".to_string()
+}
+
+fn default_login_url() -> String {
+    "https://login.microsoftonline.com".to_string()
+}
+
+fn default_graph_url() -> String {
+    "https://graph.microsoft.com/v1.0".to_string()
+}
+
+fn default_lookahead_days() -> u64 {
+    7
+}
+
+fn default_include_past_events() -> bool {
+    true
+}
+
+fn default_group_name_format() -> String {
+    "%m月%d日".to_string()
+}
+
+impl TodoConfig {
+    /// The configured calendar sources, or the default Outlook calendar if none is listed
+    pub fn sources(&self) -> Vec<CalendarSourceConfig> {
+        if self.sources.is_empty() {
+            vec![CalendarSourceConfig::Outlook(OutlookSourceConfig::default())]
+        } else {
+            self.sources.clone()
+        }
+    }
+
+    /// chrono panics on an invalid format, fall back to the default `group-name-format` then
+    fn validate(&mut self) {
+        if StrftimeItems::new(&self.group_name_format).any(|item| matches!(item, Item::Error)) {
+            warn!(
+                "Invalid `group-name-format` \"{}\" in `[todo]`, using the default",
+                self.group_name_format
+            );
+            self.group_name_format = default_group_name_format();
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum CalendarSourceConfig {
+    Outlook(OutlookSourceConfig),
+    Ics(IcsSourceConfig),
+    #[serde(rename = "caldav")]
+    CalDav(CalDavSourceConfig),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct OutlookSourceConfig {
+    #[serde(default = "default_outlook_name")]
+    pub name: String,
+    /// Use `[todo] app-id` if not set
+    pub app_id: Option<String>,
+    /// Use `[todo] tenant` if not set
+    pub tenant: Option<String>,
+    /// Token cache file of the account, relative to the state directory
+    pub token_cache: Option<PathBuf>,
+    /// Maximum number of events read from each calendar
+    #[serde(default = "default_max_items")]
+    pub max_items: usize,
+    /// The calendars to show, the default calendar of the account if empty
+    #[serde(default)]
+    pub calendars: Vec<OutlookCalendarConfig>,
+    /// Use the category colors set in Outlook, needs `MailboxSettings.Read`
+    #[serde(default)]
+    pub category_colors: bool,
+}
+
+/// A calendar of the account, of another user who shared it, or of a Microsoft 365 group
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct OutlookCalendarConfig {
+    /// Name or ID of the calendar, the default calendar of the owner if not set
+    pub calendar: Option<String>,
+    /// UPN or ID of the user owning the calendar, e.g. a room mailbox
+    pub user: Option<String>,
+    /// ID of the group, a group has only one calendar
+    pub group: Option<String>,
+}
+
+impl Default for OutlookSourceConfig {
+    fn default() -> Self {
+        Self {
+            name: default_outlook_name(),
+            app_id: None,
+            tenant: None,
+            token_cache: None,
+            max_items: default_max_items(),
+            calendars: vec![],
+            category_colors: false,
         }
     }
 }
 
+fn default_outlook_name() -> String {
+    "Outlook".to_string()
+}
+
+fn default_max_items() -> usize {
+    500
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct IcsSourceConfig {
+    #[serde(default = "default_ics_name")]
+    pub name: String,
+    pub paths: Vec<PathBuf>,
+}
+
+fn default_ics_name() -> String {
+    "iCalendar".to_string()
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct CalDavSourceConfig {
+    #[serde(default = "default_caldav_name")]
+    pub name: String,
+    /// URL of the calendar collection
+    pub url: String,
+    /// Basic auth
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Bearer auth, takes precedence over basic auth
+    pub token: Option<String>,
+}
+
+fn default_caldav_name() -> String {
+    "CalDAV".to_string()
+}
+
 fn default_app_id() -> String {
     option_env!("TODO_APP_ID")
         .unwrap_or(DEFAULT_APP_ID)
@@ -82,14 +319,25 @@ impl Default for WindowConfig {
 
 impl WindowConfig {
     pub fn format_date(&self, date: &DateTime<Local>) -> String {
+        let format = self.localize_weekday(date.weekday(), &self.date_format);
+        date.format(&format).to_string()
+    }
+
+    /// Format a date with the given format, `%A` is replaced with the name in `weekdays`
+    pub fn format_naive_date(&self, date: &NaiveDate, format: &str) -> String {
+        let format = self.localize_weekday(date.weekday(), format);
+        date.format(&format).to_string()
+    }
+
+    fn localize_weekday(&self, weekday: Weekday, format: &str) -> String {
         let binding = "".to_string();
+        // `weekdays` starts from Sunday
         let weekday = self
             .weekdays
-            .get(date.weekday() as usize)
+            .get(weekday.num_days_from_sunday() as usize)
             .unwrap_or(&binding);
         // chrono does not support %A for locale weekday names
-        let format = self.date_format.replace("%A", weekday);
-        date.format(&format).to_string()
+        format.replace("%A", weekday)
     }
 }
 
@@ -112,7 +360,22 @@ pub fn get_config<P: AsRef<Path>>(config_path: Option<P>) -> anyhow::Result<AppC
         debug!("No config file found, using default config");
         return Ok(AppConfig::default());
     }
-    Ok(toml::from_str::<AppConfig>(&config)?)
+    let mut config = toml::from_str::<AppConfig>(&config)?;
+    config.todo.validate();
+    Ok(config)
+}
+
+/// An opaque RGB color
+pub type Rgb = [u8; 3];
+
+/// Parse a `#RRGGBB` color
+pub fn parse_color(color: &str) -> Option<Rgb> {
+    let hex = color.strip_prefix('#')?;
+    if hex.len() != 6 {
+        return None;
+    }
+    let value = u32::from_str_radix(hex, 16).ok()?;
+    Some([(value >> 16) as u8, (value >> 8) as u8, value as u8])
 }
 
 pub fn get_client() -> reqwest::Client {
@@ -122,3 +385,26 @@ pub fn get_client() -> reqwest::Client {
         .build()
         .unwrap()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_invalid_group_name_format() {
+        for format in ["%m月%d日 %Q", "%m月%d日%"] {
+            let mut cfg = TodoConfig {
+                group_name_format: format.to_string(),
+                ..Default::default()
+            };
+            cfg.validate();
+            assert_eq!(cfg.group_name_format, default_group_name_format());
+        }
+        let mut cfg = TodoConfig {
+            group_name_format: "%R %m月%d日 %A".to_string(),
+            ..Default::default()
+        };
+        cfg.validate();
+        assert_eq!(cfg.group_name_format, "%R %m月%d日 %A");
+    }
+}
@@ -9,8 +9,10 @@ pub struct DeviceCodeErrorResponse {
     /// Name of the error.
     pub error: String,
     /// Description of the error.
+    #[serde(default)]
     pub error_description: String,
     /// Uri to get more information on this error.
+    #[serde(default)]
     pub error_uri: String,
 }
 
@@ -23,6 +25,76 @@ impl fmt::Display for DeviceCodeErrorResponse {
     }
 }
 
+/// Errors of polling the token endpoint, see
+/// [RFC 8628](https://www.rfc-editor.org/rfc/rfc8628#section-3.5).
+#[derive(Debug)]
+pub enum DeviceCodeError {
+    /// The user hasn't finished signing in yet, keep polling.
+    AuthorizationPending,
+    /// Polling too fast, keep polling with a longer interval.
+    SlowDown,
+    /// The user declined the sign-in.
+    AccessDenied(DeviceCodeErrorResponse),
+    /// The device code expired before the user signed in.
+    ExpiredToken,
+    /// Any other error returned by the token endpoint.
+    Response(DeviceCodeErrorResponse),
+    /// The request failed or the response couldn't be parsed.
+    Http(azure_core::Error),
+}
+
+impl DeviceCodeError {
+    /// Whether polling goes on after this error.
+    pub fn is_pending(&self) -> bool {
+        matches!(
+            self,
+            DeviceCodeError::AuthorizationPending | DeviceCodeError::SlowDown
+        )
+    }
+
+    /// Whether the device code is no longer usable and a new one has to be requested.
+    pub fn needs_new_code(&self) -> bool {
+        matches!(
+            self,
+            DeviceCodeError::AccessDenied(_) | DeviceCodeError::ExpiredToken
+        )
+    }
+}
+
+impl From<DeviceCodeErrorResponse> for DeviceCodeError {
+    fn from(rsp: DeviceCodeErrorResponse) -> Self {
+        match rsp.error.as_str() {
+            "authorization_pending" => DeviceCodeError::AuthorizationPending,
+            "slow_down" => DeviceCodeError::SlowDown,
+            // Azure AD returns `authorization_declined` instead of the `access_denied` of the RFC
+            "access_denied" | "authorization_declined" => DeviceCodeError::AccessDenied(rsp),
+            "expired_token" | "code_expired" => DeviceCodeError::ExpiredToken,
+            _ => DeviceCodeError::Response(rsp),
+        }
+    }
+}
+
+impl From<azure_core::Error> for DeviceCodeError {
+    fn from(error: azure_core::Error) -> Self {
+        DeviceCodeError::Http(error)
+    }
+}
+
+impl std::error::Error for DeviceCodeError {}
+
+impl fmt::Display for DeviceCodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeviceCodeError::AuthorizationPending => write!(f, "Authorization pending"),
+            DeviceCodeError::SlowDown => write!(f, "Polling too fast"),
+            DeviceCodeError::AccessDenied(rsp) => write!(f, "Sign-in declined: {rsp}"),
+            DeviceCodeError::ExpiredToken => write!(f, "Device code expired"),
+            DeviceCodeError::Response(rsp) => write!(f, "{rsp}"),
+            DeviceCodeError::Http(error) => write!(f, "{error}"),
+        }
+    }
+}
+
 /// A successful token response.
 #[allow(dead_code)]
 #[derive(Debug, Clone, Deserialize)]
@@ -60,3 +132,38 @@ impl DeviceCodeAuthorization {
         self.id_token.as_ref()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(error: &str) -> DeviceCodeError {
+        serde_json::from_value::<DeviceCodeErrorResponse>(serde_json::json!({ "error": error }))
+            .unwrap()
+            .into()
+    }
+
+    #[test]
+    fn test_device_code_error() {
+        assert!(matches!(
+            error("authorization_pending"),
+            DeviceCodeError::AuthorizationPending
+        ));
+        assert!(error("authorization_pending").is_pending());
+        assert!(matches!(error("slow_down"), DeviceCodeError::SlowDown));
+        assert!(error("slow_down").is_pending());
+        assert!(matches!(
+            error("authorization_declined"),
+            DeviceCodeError::AccessDenied(_)
+        ));
+        assert!(error("access_denied").needs_new_code());
+        assert!(matches!(
+            error("expired_token"),
+            DeviceCodeError::ExpiredToken
+        ));
+        assert!(error("expired_token").needs_new_code());
+        let other = error("bad_verification_code");
+        assert!(matches!(other, DeviceCodeError::Response(_)));
+        assert!(!other.is_pending() && !other.needs_new_code());
+    }
+}
@@ -17,18 +17,22 @@ use azure_core::http::RawResponse;
 use azure_core::http::Request;
 use azure_core::http::Url;
 use azure_core::json::from_json;
-use azure_core::Error;
 use futures::stream::unfold;
 use serde::Deserialize;
-use tokio::time::sleep;
+use tokio::time::{sleep, Instant};
 use url::form_urlencoded;
 
 pub use device_code_responses::*;
 
+/// Added to the poll interval on every `slow_down`, as RFC 8628 asks.
+const SLOW_DOWN_INCREMENT: Duration = Duration::from_secs(5);
+
 /// Start the device authorization grant flow.
 /// The user has only 15 minutes to sign in (the usual value for `expires_in`).
+/// `login_url` is the base URL of the identity platform, e.g. `https://login.microsoftonline.com`.
 pub async fn start<'a, 'b, T>(
     http_client: Arc<dyn HttpClient>,
+    login_url: &str,
     tenant_id: T,
     client_id: &str,
     scopes: &'b [&'b str],
@@ -37,7 +41,7 @@ where
     T: Into<Cow<'a, str>>,
 {
     let tenant_id = tenant_id.into();
-    let url = &format!("https://login.microsoftonline.com/{tenant_id}/oauth2/v2.0/devicecode");
+    let url = &format!("{login_url}/{tenant_id}/oauth2/v2.0/devicecode");
 
     let encoded = form_urlencoded::Serializer::new(String::new())
         .append_pair("client_id", client_id)
@@ -63,6 +67,7 @@ where
         interval: device_code_response.interval,
         message: device_code_response.message,
         http_client: Some(http_client),
+        login_url: login_url.to_string(),
         tenant_id,
         client_id: client_id.to_string(),
     })
@@ -82,6 +87,8 @@ pub struct DeviceCodePhaseOneResponse<'a> {
     #[serde(skip)]
     http_client: Option<Arc<dyn HttpClient>>,
     #[serde(skip)]
+    login_url: String,
+    #[serde(skip)]
     tenant_id: Cow<'a, str>,
     // We store the ClientId as string instead of the original type, because it
     // does not implement Default, and it's in another crate
@@ -95,32 +102,53 @@ impl DeviceCodePhaseOneResponse<'_> {
         &self.message
     }
 
+    /// The code the user enters at the verification URI.
+    pub fn user_code(&self) -> &str {
+        &self.user_code
+    }
+
+    /// The URI the user visits to sign in.
+    pub fn verification_uri(&self) -> &str {
+        &self.verification_uri
+    }
+
+    /// Number of seconds before the device code expires.
+    pub fn expires_in(&self) -> u64 {
+        self.expires_in
+    }
+
     /// Polls the token endpoint while the user signs in.
-    /// This will continue until either success or error is returned.
+    /// Pending errors are yielded and polling goes on, the interval grows on `slow_down`.
+    /// This will continue until either success or another error is returned, or the
+    /// code expires after `expires_in`.
     pub fn stream(
         &self,
-    ) -> Pin<Box<impl futures::Stream<Item = azure_core::Result<DeviceCodeAuthorization>> + '_>>
+    ) -> Pin<Box<impl futures::Stream<Item = Result<DeviceCodeAuthorization, DeviceCodeError>> + '_>>
     {
         #[derive(Debug, Clone, PartialEq, Eq)]
         enum NextState {
-            Continue,
+            Continue(Duration),
             Finish,
         }
 
+        let deadline = Instant::now() + Duration::from_secs(self.expires_in);
         Box::pin(unfold(
-            NextState::Continue,
+            NextState::Continue(Duration::from_secs(self.interval)),
             move |state: NextState| async move {
                 match state {
-                    NextState::Continue => {
-                        let url = &format!(
-                            "https://login.microsoftonline.com/{}/oauth2/v2.0/token",
-                            self.tenant_id,
-                        );
+                    NextState::Continue(interval) => {
+                        // No point polling once the code is gone
+                        if Instant::now() + interval >= deadline {
+                            return Some((Err(DeviceCodeError::ExpiredToken), NextState::Finish));
+                        }
+
+                        let url =
+                            &format!("{}/{}/oauth2/v2.0/token", self.login_url, self.tenant_id,);
 
                         // Throttle down as specified by Azure. This could be
                         // smarter: we could calculate the elapsed time since the
                         // last poll and wait only the delta.
-                        sleep(Duration::from_secs(self.interval)).await;
+                        sleep(interval).await;
 
                         let encoded = form_urlencoded::Serializer::new(String::new())
                             .append_pair(
@@ -133,39 +161,20 @@ impl DeviceCodePhaseOneResponse<'_> {
 
                         let http_client = self.http_client.clone().unwrap();
 
-                        match post_form(http_client.clone(), url, encoded).await {
-                            Ok(rsp) => {
-                                let rsp_status = rsp.status();
-                                let rsp_body = match rsp.into_body().collect().await {
-                                    Ok(b) => b,
-                                    Err(e) => return Some((Err(e), NextState::Finish)),
-                                };
-                                if rsp_status.is_success() {
-                                    match from_json::<_, DeviceCodeAuthorization>(&rsp_body) {
-                                        Ok(authorization) => {
-                                            Some((Ok(authorization), NextState::Finish))
-                                        }
-                                        Err(error) => Some((Err(error), NextState::Finish)),
+                        match poll(http_client, url, encoded).await {
+                            Ok(authorization) => Some((Ok(authorization), NextState::Finish)),
+                            Err(error) => {
+                                let next_state = match error {
+                                    DeviceCodeError::AuthorizationPending => {
+                                        NextState::Continue(interval)
                                     }
-                                } else {
-                                    match from_json::<_, DeviceCodeErrorResponse>(&rsp_body) {
-                                        Ok(error_rsp) => {
-                                            let next_state =
-                                                if error_rsp.error == "authorization_pending" {
-                                                    NextState::Continue
-                                                } else {
-                                                    NextState::Finish
-                                                };
-                                            Some((
-                                                Err(Error::new(ErrorKind::Credential, error_rsp)),
-                                                next_state,
-                                            ))
-                                        }
-                                        Err(error) => Some((Err(error), NextState::Finish)),
+                                    DeviceCodeError::SlowDown => {
+                                        NextState::Continue(interval + SLOW_DOWN_INCREMENT)
                                     }
-                                }
+                                    _ => NextState::Finish,
+                                };
+                                Some((Err(error), next_state))
                             }
-                            Err(error) => Some((Err(error), NextState::Finish)),
                         }
                     }
                     NextState::Finish => None,
@@ -175,6 +184,21 @@ impl DeviceCodePhaseOneResponse<'_> {
     }
 }
 
+async fn poll(
+    http_client: Arc<dyn HttpClient>,
+    url: &str,
+    form_body: String,
+) -> Result<DeviceCodeAuthorization, DeviceCodeError> {
+    let rsp = post_form(http_client, url, form_body).await?;
+    let rsp_status = rsp.status();
+    let rsp_body = rsp.into_body().collect().await?;
+    if rsp_status.is_success() {
+        Ok(from_json::<_, DeviceCodeAuthorization>(&rsp_body)?)
+    } else {
+        Err(from_json::<_, DeviceCodeErrorResponse>(&rsp_body)?.into())
+    }
+}
+
 async fn post_form(
     http_client: Arc<dyn HttpClient>,
     url: &str,
@@ -202,6 +226,7 @@ mod tests {
             azure_core::http::new_http_client(),
             "UNUSED",
             "UNUSED",
+            "UNUSED",
             &[],
         ));
     }
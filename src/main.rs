@@ -17,7 +17,7 @@ mod weather;
 
 use config::{get_config, TodoConfig, WeatherConfig, WindowConfig};
 use wallpaper::get_wallpaper;
-use weather::get_weather;
+use weather::{get_provider, temperature_path, WeatherProvider};
 
 use crate::config::get_config_path;
 
@@ -56,35 +56,167 @@ async fn update_time(handle: Weak<AppWindow>, cfg: WindowConfig) {
     }
 }
 
+fn format_clock(time: Option<NaiveTime>) -> slint::SharedString {
+    time.map(|time| time.format("%H:%M").to_string())
+        .unwrap_or_else(|| "--:--".to_string())
+        .into()
+}
+
+/// Load a weather icon from the embedded assets, must be called in the event loop
+fn weather_icon(icon: &str) -> Option<slint::Image> {
+    let file = Assets::get(&format!("{icon}.svg"))?;
+    slint::Image::load_from_svg_data(&file.data).ok()
+}
+
 async fn update_weather(handle: Weak<AppWindow>, cfg: WeatherConfig) {
+    let provider = match get_provider(&cfg) {
+        Ok(provider) => provider,
+        Err(e) => {
+            warn!("Weather is disabled, error: {e}");
+            return;
+        }
+    };
     loop {
         // Update weather every hour
         debug!("Getting weather");
-        match get_weather(
-            &cfg.api_host,
-            &cfg.location,
-            &cfg.app_id,
-            &cfg.key_id,
-            &cfg.signing_key,
-        )
-        .await
-        {
+        match provider.get_weather().await {
             Ok(weather) => {
-                let icon_path = format!("{}.svg", weather.weather_icon);
-                debug!("Weather icon path: {icon_path}");
-                if let Some(file) = Assets::get(&icon_path) {
-                    handle
-                        .upgrade_in_event_loop(move |ui| {
-                            ui.global::<AppData>()
-                                .set_temperature(weather.temperature as i32);
-                            ui.global::<AppData>().set_high(weather.high as i32);
-                            ui.global::<AppData>().set_low(weather.low as i32);
-                            if let Ok(image) = slint::Image::load_from_svg_data(&file.data) {
-                                ui.global::<AppData>().set_weather_icon(image);
-                            }
-                        })
-                        .unwrap();
+                debug!("Weather icon: {}", weather.icon());
+                let forecast_days = cfg.forecast_days;
+                // Each label covers 3 hours
+                let hourly = &weather.hourly[..weather.hourly.len().min(cfg.hourly_hours / 3 * 3)];
+                let hourly_precipitation: Vec<f32> =
+                    hourly.iter().map(|hour| hour.precipitation).collect();
+                let hourly_temperature_path = temperature_path(hourly);
+                let hourly_labels: Vec<slint::SharedString> = hourly
+                    .chunks(3)
+                    .map(|hours| {
+                        format!(
+                            "{}时 {}°",
+                            hours[0].time.hour(),
+                            hours[0].temperature.round()
+                        )
+                        .into()
+                    })
+                    .collect();
+                let warnings: Vec<WarningData> = weather
+                    .warnings
+                    .iter()
+                    .map(|warning| {
+                        let [r, g, b] = warning.color();
+                        WarningData {
+                            type_name: warning.type_name.as_str().into(),
+                            title: warning.title.as_str().into(),
+                            text: warning.text.as_str().into(),
+                            color: slint::Color::from_rgb_u8(r, g, b),
+                            dark_text: warning.dark_text(),
+                        }
+                    })
+                    .collect();
+                if !warnings.is_empty() {
+                    info!("{} active weather warnings", warnings.len());
                 }
+                let minutely_precipitation: Vec<f32> = weather
+                    .minutely
+                    .iter()
+                    .map(|minute| minute.intensity)
+                    .collect();
+                handle
+                    .upgrade_in_event_loop(move |ui| {
+                        ui.global::<AppData>()
+                            .set_temperature(weather.temperature as i32);
+                        ui.global::<AppData>().set_high(weather.high as i32);
+                        ui.global::<AppData>().set_low(weather.low as i32);
+                        if let Some(image) = weather_icon(weather.icon()) {
+                            ui.global::<AppData>().set_weather_icon(image);
+                        }
+                        let forecast: Vec<ForecastData> = weather
+                            .forecast
+                            .iter()
+                            .take(forecast_days)
+                            .map(|day| ForecastData {
+                                weekday: day.date.weekday().num_days_from_sunday() as i32,
+                                month: day.date.month() as i32,
+                                day: day.date.day() as i32,
+                                high: day.high.round() as i32,
+                                low: day.low.round() as i32,
+                                day_icon: weather_icon(day.icon_day()).unwrap_or_default(),
+                                night_icon: weather_icon(day.icon_night()).unwrap_or_default(),
+                                precipitation: day.precipitation,
+                            })
+                            .collect();
+                        ui.global::<AppData>()
+                            .set_forecast(ModelRc::new(VecModel::from(forecast)));
+                        ui.global::<AppData>()
+                            .set_hourly_precipitation(ModelRc::new(VecModel::from(
+                                hourly_precipitation,
+                            )));
+                        ui.global::<AppData>()
+                            .set_hourly_temperature_path(hourly_temperature_path.into());
+                        ui.global::<AppData>()
+                            .set_hourly_labels(ModelRc::new(VecModel::from(hourly_labels)));
+                        ui.global::<AppData>()
+                            .set_minutely_precipitation(ModelRc::new(VecModel::from(
+                                minutely_precipitation,
+                            )));
+                        ui.global::<AppData>().set_precipitation_summary(
+                            weather.precipitation_summary.as_str().into(),
+                        );
+                        ui.global::<AppData>()
+                            .set_warnings(ModelRc::new(VecModel::from(warnings)));
+                        let data = ui.global::<AppData>();
+                        if let Some(today) = weather.today() {
+                            data.set_sun_moon_visible(true);
+                            data.set_sunrise_text(format_clock(today.sunrise));
+                            data.set_sunset_text(format_clock(today.sunset));
+                            data.set_moonrise_text(format_clock(today.moonrise));
+                            data.set_moonset_text(format_clock(today.moonset));
+                            data.set_moon_phase(
+                                today
+                                    .moon_phase
+                                    .map(|phase| phase.name())
+                                    .unwrap_or("")
+                                    .into(),
+                            );
+                            data.set_moon_phase_icon(
+                                today
+                                    .moon_phase
+                                    .and_then(|phase| weather_icon(phase.icon()))
+                                    .unwrap_or_default(),
+                            );
+                            let to_time = |time: NaiveTime| Time {
+                                hour: time.hour() as i32,
+                                minute: time.minute() as i32,
+                                second: time.second() as i32,
+                            };
+                            if let (Some(sunrise), Some(sunset)) = (today.sunrise, today.sunset) {
+                                data.set_sunrise(to_time(sunrise));
+                                data.set_sunset(to_time(sunset));
+                            }
+                            data.set_sun_times_known(
+                                today.sunrise.is_some() && today.sunset.is_some(),
+                            );
+                        } else {
+                            data.set_sun_moon_visible(false);
+                            data.set_sun_times_known(false);
+                        }
+                        data.set_air_quality_visible(weather.air_quality.is_some());
+                        if let Some(air_quality) = &weather.air_quality {
+                            let [r, g, b] = air_quality.color();
+                            data.set_aqi(air_quality.aqi as i32);
+                            data.set_aqi_category(air_quality.category.as_str().into());
+                            data.set_aqi_primary(
+                                air_quality
+                                    .primary_pollutant
+                                    .clone()
+                                    .unwrap_or_default()
+                                    .into(),
+                            );
+                            data.set_aqi_color(slint::Color::from_rgb_u8(r, g, b));
+                            data.set_aqi_dark_text(air_quality.dark_text());
+                        }
+                    })
+                    .unwrap();
             }
             Err(e) => {
                 warn!("Failed to get weather, error: {e}");
@@ -151,6 +283,82 @@ async fn update_wallpaper(handle: Weak<AppWindow>) {
     }
 }
 
+/// Render `text` as a black-on-white QR code, one pixel per module
+fn qr_code_image(text: &str) -> anyhow::Result<SharedPixelBuffer<Rgb8Pixel>> {
+    // The quiet zone around the code required by the spec
+    const QUIET_ZONE: usize = 4;
+    let code = qrcode::QrCode::new(text.as_bytes())?;
+    let colors = code.to_colors();
+    let width = code.width();
+    let size = width + QUIET_ZONE * 2;
+    let mut buffer = SharedPixelBuffer::<Rgb8Pixel>::new(size as u32, size as u32);
+    for (i, pixel) in buffer.make_mut_slice().iter_mut().enumerate() {
+        let (x, y) = (i % size, i / size);
+        let dark = (QUIET_ZONE..QUIET_ZONE + width).contains(&x)
+            && (QUIET_ZONE..QUIET_ZONE + width).contains(&y)
+            && colors[(y - QUIET_ZONE) * width + x - QUIET_ZONE] == qrcode::Color::Dark;
+        let value = if dark { 0 } else { 255 };
+        *pixel = Rgb8Pixel::new(value, value, value);
+    }
+    Ok(buffer)
+}
+
+async fn update_sign_in(handle: Weak<AppWindow>) {
+    let mut prompts = todo::sign_in_prompts();
+    loop {
+        // One sign-in at a time, the next one shows up when the first is done
+        let prompt = prompts.borrow_and_update().first().cloned();
+        match &prompt {
+            Some(prompt) => {
+                info!("Showing sign-in prompt with code {}", prompt.user_code);
+                let qr_code = qr_code_image(&prompt.verification_uri)
+                    .inspect_err(|e| warn!("Failed to generate QR code, error: {e}"))
+                    .ok();
+                let prompt = prompt.clone();
+                handle
+                    .upgrade_in_event_loop(move |ui| {
+                        let data = ui.global::<AppData>();
+                        data.set_sign_in_account(prompt.account.into());
+                        data.set_sign_in_code(prompt.user_code.into());
+                        data.set_sign_in_uri(prompt.verification_uri.into());
+                        if let Some(qr_code) = qr_code {
+                            data.set_sign_in_qr_code(slint::Image::from_rgb8(qr_code));
+                        }
+                        data.set_sign_in_visible(true);
+                    })
+                    .unwrap();
+            }
+            None => {
+                handle
+                    .upgrade_in_event_loop(|ui| {
+                        ui.global::<AppData>().set_sign_in_visible(false);
+                    })
+                    .unwrap();
+            }
+        }
+        // Count down every second until the prompt changes
+        loop {
+            if let Some(prompt) = &prompt {
+                let remaining = (prompt.expires_on - Utc::now()).num_seconds().max(0) as i32;
+                handle
+                    .upgrade_in_event_loop(move |ui| {
+                        ui.global::<AppData>().set_sign_in_remaining(remaining);
+                    })
+                    .unwrap();
+            }
+            tokio::select! {
+                changed = prompts.changed() => {
+                    if changed.is_err() {
+                        return;
+                    }
+                    break;
+                }
+                _ = sleep(Duration::from_secs(1)) => {}
+            }
+        }
+    }
+}
+
 impl From<todo::Time> for Time {
     fn from(time: todo::Time) -> Self {
         Self {
@@ -161,14 +369,62 @@ impl From<todo::Time> for Time {
     }
 }
 
+impl From<todo::Date> for Date {
+    fn from(date: todo::Date) -> Self {
+        Self {
+            year: date.year,
+            month: date.month,
+            day: date.day,
+        }
+    }
+}
+
 impl From<todo::TodoItemData> for TodoItemData {
     fn from(item: todo::TodoItemData) -> Self {
         Self {
             active: item.active,
+            day_count: item.day_count,
+            day_index: item.day_index,
+            end_date: item.end_date.into(),
             end_time: item.end_time.into(),
             show_time: item.show_time,
+            start_date: item.start_date.into(),
             start_time: item.start_time.into(),
             text: item.text.into(),
+            location: item.location.into(),
+            organizer: item.organizer.into(),
+            online_meeting: item.online_meeting,
+            show_as: item.show_as.into(),
+            response: item.response.into(),
+            calendar_name: item.calendar_name.into(),
+            color: item
+                .color
+                .map(|[r, g, b]| slint::Color::from_rgb_u8(r, g, b))
+                .unwrap_or_default(),
+        }
+    }
+}
+
+impl From<todo::ShowAs> for EventShowAs {
+    fn from(show_as: todo::ShowAs) -> Self {
+        match show_as {
+            todo::ShowAs::Free => Self::Free,
+            todo::ShowAs::Tentative => Self::Tentative,
+            todo::ShowAs::Busy => Self::Busy,
+            todo::ShowAs::OutOfOffice => Self::OutOfOffice,
+            todo::ShowAs::WorkingElsewhere => Self::WorkingElsewhere,
+        }
+    }
+}
+
+impl From<todo::Response> for EventResponse {
+    fn from(response: todo::Response) -> Self {
+        match response {
+            todo::Response::None => Self::None,
+            todo::Response::NotResponded => Self::NotResponded,
+            todo::Response::Accepted => Self::Accepted,
+            todo::Response::Tentative => Self::Tentative,
+            todo::Response::Declined => Self::Declined,
         }
     }
 }
@@ -184,34 +440,21 @@ impl From<todo::TodoItemGroupData> for TodoItemGroupData {
     }
 }
 
-async fn update_todo(handle: Weak<AppWindow>, cfg: TodoConfig) {
-    loop {
-        // Update todo every 10 minutes
-        debug!("Getting todo list");
-        match todo::get_todo_list(cfg.app_id.clone()).await {
-            Ok(todo) => {
-                handle
-                    .upgrade_in_event_loop(move |ui| {
-                        let groups: Vec<TodoItemGroupData> =
-                            todo.into_iter().map(|list| list.into()).collect();
-                        ui.global::<AppData>()
-                            .set_todo_list(ModelRc::from(Rc::new(VecModel::from(groups))));
-                    })
-                    .unwrap();
-            }
-            Err(e) => {
-                warn!("Failed to get todo list, error: {e}");
-            }
-        }
-
-        const INTERVAL: Duration = Duration::from_secs(600);
-        debug!(
-            "The next run of todo update is set to {}",
-            Utc::now() + INTERVAL
-        );
-        // Sleep for 10 minutes
-        sleep(INTERVAL).await;
-    }
+async fn update_todo(handle: Weak<AppWindow>, cfg: TodoConfig, window_cfg: WindowConfig) {
+    let sources = todo::get_sources(&cfg);
+    // Update todo every 10 minutes
+    const INTERVAL: Duration = Duration::from_secs(600);
+    todo::update_todo_list(&sources, &cfg, &window_cfg, INTERVAL, |todo| {
+        handle
+            .upgrade_in_event_loop(move |ui| {
+                let groups: Vec<TodoItemGroupData> =
+                    todo.into_iter().map(|list| list.into()).collect();
+                ui.global::<AppData>()
+                    .set_todo_list(ModelRc::from(Rc::new(VecModel::from(groups))));
+            })
+            .unwrap();
+    })
+    .await;
 }
 
 fn main() -> anyhow::Result<()> {
@@ -231,6 +474,54 @@ fn main() -> anyhow::Result<()> {
         open_editor: bool,
         #[command(flatten)]
         verbose: clap_verbosity_flag::Verbosity,
+        #[command(subcommand)]
+        command: Option<Command>,
+    }
+
+    #[derive(clap::Subcommand, Debug, Clone)]
+    enum Command {
+        /// Manage the sign-in of the Outlook accounts without starting the UI
+        Auth {
+            #[command(subcommand)]
+            action: AuthCommand,
+        },
+        /// Work with the calendars of the Outlook accounts without starting the UI
+        Calendars {
+            #[command(subcommand)]
+            action: CalendarsCommand,
+        },
+    }
+
+    #[derive(clap::Subcommand, Debug, Clone)]
+    enum CalendarsCommand {
+        /// List the calendars, with the names and IDs used in `calendars` of the config
+        List {
+            /// Only the account with this name, all Outlook accounts if not set
+            #[arg(long)]
+            account: Option<String>,
+        },
+    }
+
+    #[derive(clap::Subcommand, Debug, Clone)]
+    enum AuthCommand {
+        /// Sign in with the device code
+        Login {
+            /// Only the account with this name, all Outlook accounts if not set
+            #[arg(long)]
+            account: Option<String>,
+        },
+        /// Show the account, tenant, scopes and expiry of the saved tokens
+        Status {
+            /// Only the account with this name, all Outlook accounts if not set
+            #[arg(long)]
+            account: Option<String>,
+        },
+        /// Delete the saved tokens
+        Logout {
+            /// Only the account with this name, all Outlook accounts if not set
+            #[arg(long)]
+            account: Option<String>,
+        },
     }
 
     let cli = Args::parse();
@@ -249,6 +540,24 @@ fn main() -> anyhow::Result<()> {
 
     let cfg = get_config(cli.config_path)?;
 
+    if let Some(command) = cli.command {
+        let runtime = tokio::runtime::Runtime::new()?;
+        return match command {
+            Command::Auth {
+                action: AuthCommand::Login { account },
+            } => runtime.block_on(todo::login(&cfg.todo, account.as_deref())),
+            Command::Auth {
+                action: AuthCommand::Status { account },
+            } => runtime.block_on(todo::auth_status(&cfg.todo, account.as_deref())),
+            Command::Auth {
+                action: AuthCommand::Logout { account },
+            } => runtime.block_on(todo::logout(&cfg.todo, account.as_deref())),
+            Command::Calendars {
+                action: CalendarsCommand::List { account },
+            } => runtime.block_on(todo::list_calendars(&cfg.todo, account.as_deref())),
+        };
+    }
+
     if cfg.window.full_screen {
         std::env::set_var("SLINT_FULLSCREEN", "1");
     }
@@ -281,11 +590,24 @@ fn main() -> anyhow::Result<()> {
         update_weather(handle, cfg_clone).await;
     });
 
+    let handle = ui.as_weak();
+    rt.spawn(async move {
+        info!("Starting sign-in prompt task");
+        update_sign_in(handle).await;
+    });
+
     let handle = ui.as_weak();
     let cfg_clone = cfg.todo.clone();
+    let window_cfg_clone = cfg.window.clone();
     rt.spawn(async move {
         info!("Starting todo update task");
-        update_todo(handle, cfg_clone).await;
+        update_todo(handle, cfg_clone, window_cfg_clone).await;
+    });
+
+    let cfg_clone = cfg.todo.clone();
+    rt.spawn(async move {
+        info!("Starting token refresh task");
+        todo::refresh_tokens(&todo::get_sources(&cfg_clone)).await;
     });
 
     ui.run()?;
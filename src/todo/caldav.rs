@@ -0,0 +1,246 @@
+use chrono::{DateTime, Utc};
+use log::{debug, info, warn};
+use quick_xml::{events::Event, Reader};
+
+use super::ics::{expand_events, parse_calendar};
+use super::{CalendarEvent, CalendarSource};
+use crate::config::{get_client, CalDavSourceConfig};
+
+/// Calendar collection on a CalDAV server, e.g. Nextcloud or Radicale
+#[derive(Debug, Clone)]
+pub struct CalDavSource {
+    name: String,
+    url: String,
+    auth: CalDavAuth,
+}
+
+#[derive(Debug, Clone)]
+enum CalDavAuth {
+    None,
+    Basic { username: String, password: String },
+    Bearer { token: String },
+}
+
+impl CalDavSource {
+    pub fn new(cfg: &CalDavSourceConfig) -> Self {
+        let auth = match (&cfg.token, &cfg.username) {
+            (Some(token), _) => CalDavAuth::Bearer {
+                token: token.clone(),
+            },
+            (None, Some(username)) => CalDavAuth::Basic {
+                username: username.clone(),
+                password: cfg.password.clone().unwrap_or_default(),
+            },
+            (None, None) => CalDavAuth::None,
+        };
+        Self {
+            name: cfg.name.clone(),
+            url: cfg.url.clone(),
+            auth,
+        }
+    }
+}
+
+fn calendar_query(start: DateTime<Utc>, end: DateTime<Utc>) -> String {
+    // Recurring events are returned as a whole if any instance falls in the range,
+    // they are expanded locally just like the iCalendar files
+    format!(
+        r#"<?xml version="1.0" encoding="utf-8" ?>
+<c:calendar-query xmlns:d="DAV:" xmlns:c="urn:ietf:params:xml:ns:caldav">
+  <d:prop>
+    <d:getetag/>
+    <c:calendar-data/>
+  </d:prop>
+  <c:filter>
+    <c:comp-filter name="VCALENDAR">
+      <c:comp-filter name="VEVENT">
+        <c:time-range start="{}" end="{}"/>
+      </c:comp-filter>
+    </c:comp-filter>
+  </c:filter>
+</c:calendar-query>"#,
+        start.format("%Y%m%dT%H%M%SZ"),
+        end.format("%Y%m%dT%H%M%SZ"),
+    )
+}
+
+/// Extract the content of all `calendar-data` elements in a multistatus response
+fn parse_multistatus(body: &str) -> anyhow::Result<Vec<String>> {
+    let mut reader = Reader::from_str(body);
+    let mut calendars = vec![];
+    let mut current: Option<String> = None;
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) if e.local_name().as_ref() == b"calendar-data" => {
+                current = Some(String::new());
+            }
+            Event::Text(text) => {
+                if let Some(current) = current.as_mut() {
+                    current.push_str(&text.unescape()?);
+                }
+            }
+            Event::CData(data) => {
+                if let Some(current) = current.as_mut() {
+                    current.push_str(&String::from_utf8_lossy(&data.into_inner()));
+                }
+            }
+            Event::End(e) if e.local_name().as_ref() == b"calendar-data" => {
+                calendars.extend(current.take());
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(calendars)
+}
+
+impl CalendarSource for CalDavSource {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn get_events(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> anyhow::Result<Vec<CalendarEvent>> {
+        let client = get_client();
+        debug!("Requesting calendar-query from {}", self.url);
+        let req = client
+            .request(reqwest::Method::from_bytes(b"REPORT")?, &self.url)
+            .header("Depth", "1")
+            .header("Content-Type", "application/xml; charset=utf-8")
+            .body(calendar_query(start, end));
+        let req = match &self.auth {
+            CalDavAuth::None => req,
+            CalDavAuth::Basic { username, password } => req.basic_auth(username, Some(password)),
+            CalDavAuth::Bearer { token } => req.bearer_auth(token),
+        };
+        let body = req.send().await?.error_for_status()?.text().await?;
+        let mut events = vec![];
+        for calendar in parse_multistatus(&body)? {
+            match parse_calendar(&calendar) {
+                Ok(mut resource_events) => events.append(&mut resource_events),
+                // Skip the broken resource instead of the whole calendar
+                Err(e) => warn!("Failed to parse a resource from {}, error: {e}", self.name),
+            }
+        }
+        let events = expand_events(&events, start, end);
+        info!("{} items retrieved from {}", events.len(), self.name);
+        Ok(events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_multistatus() {
+        let body = r#"<?xml version="1.0" encoding="utf-8"?>
+<multistatus xmlns="DAV:" xmlns:C="urn:ietf:params:xml:ns:caldav">
+  <response>
+    <href>/user/calendar/a.ics</href>
+    <propstat>
+      <prop>
+        <getetag>"1"</getetag>
+        <C:calendar-data>BEGIN:VCALENDAR
+BEGIN:VEVENT
+UID:a
+SUMMARY:Lunch &amp; learn
+DTSTART:20240603T040000Z
+DTEND:20240603T050000Z
+END:VEVENT
+END:VCALENDAR
+</C:calendar-data>
+      </prop>
+      <status>HTTP/1.1 200 OK</status>
+    </propstat>
+  </response>
+  <response>
+    <href>/user/calendar/b.ics</href>
+    <propstat>
+      <prop>
+        <C:calendar-data><![CDATA[BEGIN:VCALENDAR
+BEGIN:VEVENT
+UID:b
+SUMMARY:Review
+DTSTART:20240604T040000Z
+END:VEVENT
+END:VCALENDAR
+]]></C:calendar-data>
+      </prop>
+    </propstat>
+  </response>
+</multistatus>"#;
+        let calendars = parse_multistatus(body).unwrap();
+        assert_eq!(calendars.len(), 2);
+        let events = parse_calendar(&calendars[0]).unwrap();
+        let start = DateTime::parse_from_rfc3339("2024-06-03T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let events = expand_events(&events, start, start + chrono::Duration::days(7));
+        assert_eq!(events[0].subject, "Lunch & learn");
+        assert!(calendars[1].contains("UID:b"));
+    }
+
+    #[tokio::test]
+    async fn test_malformed_resource() {
+        use wiremock::matchers::method;
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        let resource = |data: &str| {
+            format!(
+                "<response><propstat><prop><C:calendar-data><![CDATA[{data}]]></C:calendar-data></prop></propstat></response>"
+            )
+        };
+        let body = format!(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<multistatus xmlns="DAV:" xmlns:C="urn:ietf:params:xml:ns:caldav">{}{}</multistatus>"#,
+            resource("BEGIN:VCALENDAR\nBEGIN:VEVENT\nUID:a\nSUMMARY:Lunch\n"),
+            resource(
+                "BEGIN:VCALENDAR\nBEGIN:VEVENT\nUID:b\nSUMMARY:Review\nDTSTART:20240604T040000Z\nEND:VEVENT\nEND:VCALENDAR\n"
+            ),
+        );
+        Mock::given(method("REPORT"))
+            .respond_with(ResponseTemplate::new(207).set_body_string(body))
+            .mount(&server)
+            .await;
+        let source = CalDavSource::new(&CalDavSourceConfig {
+            name: "CalDAV".to_string(),
+            url: server.uri(),
+            username: None,
+            password: None,
+            token: None,
+        });
+        let start = DateTime::parse_from_rfc3339("2024-06-03T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let events = source
+            .get_events(start, start + chrono::Duration::days(7))
+            .await
+            .unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].subject, "Review");
+        assert!(parse_calendar("BEGIN:VCALENDAR\nBEGIN:VEVENT\nUID:a\nSUMMARY:Lunch\n").is_err());
+    }
+
+    #[tokio::test]
+    #[ignore = "Needs a CalDAV server, e.g. `radicale --storage-filesystem-folder=/tmp/radicale`"]
+    async fn test_get_events() {
+        let source = CalDavSource::new(&CalDavSourceConfig {
+            name: "CalDAV".to_string(),
+            url: std::env::var("CALDAV_URL").unwrap(),
+            username: std::env::var("CALDAV_USERNAME").ok(),
+            password: std::env::var("CALDAV_PASSWORD").ok(),
+            token: None,
+        });
+        let now = Utc::now();
+        let events = source
+            .get_events(now, now + chrono::Duration::days(7))
+            .await
+            .unwrap();
+        println!("{events:?}");
+    }
+}
@@ -0,0 +1,338 @@
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use log::warn;
+
+// Header of an encrypted file, followed by the salt, the nonce and the ciphertext
+const MAGIC: &[u8] = b"TSTC1";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+// Tried in order when no passphrase or key file is configured
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+const MACHINE_ID_FILES: &[&str] = &["/etc/machine-id", "/var/lib/dbus/machine-id"];
+
+/// Where the secret the encryption key is derived from comes from
+#[derive(Clone)]
+pub enum KeySource {
+    Passphrase(String),
+    File(PathBuf),
+    /// The machine id of the system. Older versions generated a random key in `legacy_key`
+    /// where they couldn't read one, it's still read so their caches keep working
+    MachineId {
+        legacy_key: PathBuf,
+    },
+}
+
+impl std::fmt::Debug for KeySource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeySource::Passphrase(_) => write!(f, "Passphrase(..)"),
+            KeySource::File(path) => write!(f, "File({path:?})"),
+            KeySource::MachineId { legacy_key } => write!(f, "MachineId({legacy_key:?})"),
+        }
+    }
+}
+
+impl KeySource {
+    fn secret(&self) -> anyhow::Result<Vec<u8>> {
+        match self {
+            KeySource::Passphrase(passphrase) => Ok(passphrase.as_bytes().to_vec()),
+            KeySource::File(path) => Ok(std::fs::read(path)?),
+            KeySource::MachineId { legacy_key } => {
+                if let Some(id) = machine_id() {
+                    return Ok(id.into_bytes());
+                }
+                // A key next to the data it encrypts protects nothing, don't create a new one
+                if legacy_key.exists() {
+                    warn!(
+                        "No machine id, using the key in {} which sits next to the token caches, \
+                         set `token-passphrase` or `token-key-file` in `[todo]` to protect them",
+                        legacy_key.display()
+                    );
+                    return Ok(std::fs::read(legacy_key)?);
+                }
+                anyhow::bail!(
+                    "No machine id to encrypt the token cache with, \
+                     set `token-passphrase` or `token-key-file` in `[todo]`"
+                )
+            }
+        }
+    }
+}
+
+/// `/etc/machine-id` on Linux and the BSDs
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+fn machine_id() -> Option<String> {
+    MACHINE_ID_FILES.iter().find_map(|path| {
+        let id = std::fs::read_to_string(path).ok()?;
+        Some(id.trim().to_string()).filter(|id| !id.is_empty())
+    })
+}
+
+/// `MachineGuid` in the registry, the 64-bit view even from a 32-bit build
+#[cfg(target_os = "windows")]
+fn machine_id() -> Option<String> {
+    use std::os::windows::process::CommandExt;
+    // Don't flash a console window over the GUI
+    const CREATE_NO_WINDOW: u32 = 0x0800_0000;
+    let output = std::process::Command::new("reg")
+        .creation_flags(CREATE_NO_WINDOW)
+        .args([
+            "query",
+            r"HKLM\SOFTWARE\Microsoft\Cryptography",
+            "/v",
+            "MachineGuid",
+            "/reg:64",
+        ])
+        .output()
+        .ok()?;
+    parse_machine_guid(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// `IOPlatformUUID` of the platform expert device
+#[cfg(target_os = "macos")]
+fn machine_id() -> Option<String> {
+    let output = std::process::Command::new("ioreg")
+        .args(["-rd1", "-c", "IOPlatformExpertDevice"])
+        .output()
+        .ok()?;
+    parse_platform_uuid(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// The value in `    MachineGuid    REG_SZ    <guid>` printed by `reg query`
+#[cfg(any(target_os = "windows", test))]
+fn parse_machine_guid(output: &str) -> Option<String> {
+    output
+        .lines()
+        .map(str::split_whitespace)
+        .find_map(
+            |mut fields| match (fields.next(), fields.next(), fields.next()) {
+                (Some("MachineGuid"), Some("REG_SZ"), Some(guid)) => Some(guid.to_string()),
+                _ => None,
+            },
+        )
+}
+
+/// The value in `"IOPlatformUUID" = "<uuid>"` printed by `ioreg`
+#[cfg(any(target_os = "macos", test))]
+fn parse_platform_uuid(output: &str) -> Option<String> {
+    output.lines().find_map(|line| {
+        let (name, value) = line.split_once('=')?;
+        (name.trim() == "\"IOPlatformUUID\"")
+            .then(|| value.trim().trim_matches('"').to_string())
+            .filter(|uuid| !uuid.is_empty())
+    })
+}
+
+fn derive_key(secret: &[u8], salt: &[u8]) -> anyhow::Result<Key<Aes256Gcm>> {
+    let mut key = Key::<Aes256Gcm>::default();
+    argon2::Argon2::default()
+        .hash_password_into(secret, salt, &mut key)
+        .map_err(|e| anyhow::anyhow!("Failed to derive key: {e}"))?;
+    Ok(key)
+}
+
+/// Whether the data is written by `encrypt`, otherwise it's a plaintext cache
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// Encrypts the token cache of one store. Argon2 is slow on purpose, so the key is derived
+/// once and reused as long as the salt in the file stays the same
+pub struct TokenCipher {
+    key_source: KeySource,
+    key: Mutex<Option<([u8; SALT_LEN], Key<Aes256Gcm>)>>,
+}
+
+impl std::fmt::Debug for TokenCipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TokenCipher")
+            .field("key_source", &self.key_source)
+            .finish_non_exhaustive()
+    }
+}
+
+impl TokenCipher {
+    pub fn new(key_source: &KeySource) -> Self {
+        Self {
+            key_source: key_source.clone(),
+            key: Mutex::new(None),
+        }
+    }
+
+    /// The key for `salt`, derived only if it's not the one derived last time
+    fn key(&self, salt: &[u8; SALT_LEN]) -> anyhow::Result<Key<Aes256Gcm>> {
+        let mut cached = self.key.lock().unwrap();
+        if let Some((cached_salt, key)) = cached.as_ref() {
+            if cached_salt == salt {
+                return Ok(*key);
+            }
+        }
+        let key = derive_key(&self.key_source.secret()?, salt)?;
+        *cached = Some((*salt, key));
+        Ok(key)
+    }
+
+    pub fn encrypt(&self, plaintext: &[u8]) -> anyhow::Result<Vec<u8>> {
+        // Every file gets a new nonce, so the salt and the key can be kept
+        let salt = match self.key.lock().unwrap().as_ref() {
+            Some((salt, _)) => *salt,
+            None => {
+                let mut salt = [0u8; SALT_LEN];
+                OsRng.fill_bytes(&mut salt);
+                salt
+            }
+        };
+        let cipher = Aes256Gcm::new(&self.key(&salt)?);
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(&nonce, plaintext)
+            .map_err(|_| anyhow::anyhow!("Failed to encrypt"))?;
+        Ok([MAGIC, &salt, &nonce, &ciphertext].concat())
+    }
+
+    pub fn decrypt(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let data = data
+            .strip_prefix(MAGIC)
+            .filter(|data| data.len() > SALT_LEN + NONCE_LEN)
+            .ok_or(anyhow::anyhow!("Not an encrypted token cache"))?;
+        let (salt, data) = data.split_at(SALT_LEN);
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+        let cipher = Aes256Gcm::new(&self.key(salt.try_into()?)?);
+        cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| {
+                anyhow::anyhow!("Failed to decrypt, the key has changed or the file is broken")
+            })
+    }
+}
+
+/// Write a file only the current user can read. The data goes to a temporary file first and
+/// replaces the old file in one rename, so a crash in the middle never leaves a broken file
+pub fn write_private(path: &Path, data: &[u8]) -> anyhow::Result<()> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    std::fs::create_dir_all(dir)?;
+    let file_name = path
+        .file_name()
+        .ok_or(anyhow::anyhow!("Invalid path {}", path.display()))?;
+    let mut temp_name = file_name.to_os_string();
+    temp_name.push(".tmp");
+    let temp_path = path.with_file_name(temp_name);
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(&temp_path)?;
+    // `mode` only applies to new files, a temporary file left by a crash may have another one
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    }
+    std::io::Write::write_all(&mut file, data)?;
+    file.sync_all()?;
+    drop(file);
+    std::fs::rename(&temp_path, path)?;
+    // Make the rename itself durable
+    #[cfg(unix)]
+    if let Ok(dir) = std::fs::File::open(dir) {
+        let _ = dir.sync_all();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encrypt() {
+        let key = KeySource::Passphrase("correct horse battery staple".to_string());
+        let cipher = TokenCipher::new(&key);
+        let data = cipher.encrypt(b"{\"access_token\":\"a\"}").unwrap();
+        assert!(is_encrypted(&data));
+        assert!(!is_encrypted(b"{\"access_token\":\"a\"}"));
+        assert_eq!(cipher.decrypt(&data).unwrap(), b"{\"access_token\":\"a\"}");
+        // Another store with the same key reads it too
+        assert_eq!(
+            TokenCipher::new(&key).decrypt(&data).unwrap(),
+            b"{\"access_token\":\"a\"}"
+        );
+        let wrong = TokenCipher::new(&KeySource::Passphrase("wrong".to_string()));
+        assert!(wrong.decrypt(&data).is_err());
+    }
+
+    #[test]
+    fn test_key_reused() {
+        let cipher = TokenCipher::new(&KeySource::Passphrase("test".to_string()));
+        let first = cipher.encrypt(b"first").unwrap();
+        let second = cipher.encrypt(b"second").unwrap();
+        // Same salt, so the key is derived only once, but a new nonce every time
+        let salt = MAGIC.len()..MAGIC.len() + SALT_LEN;
+        let nonce = salt.end..salt.end + NONCE_LEN;
+        assert_eq!(first[salt.clone()], second[salt]);
+        assert_ne!(first[nonce.clone()], second[nonce]);
+        assert_eq!(cipher.decrypt(&first).unwrap(), b"first");
+    }
+
+    #[test]
+    fn test_no_machine_id() {
+        let legacy_key =
+            std::env::temp_dir().join(format!("todo-station-{}-legacy.key", std::process::id()));
+        let key_source = KeySource::MachineId {
+            legacy_key: legacy_key.clone(),
+        };
+        if machine_id().is_some() {
+            assert!(key_source.secret().is_ok());
+        } else {
+            // No key is generated next to the caches
+            assert!(key_source.secret().is_err());
+            assert!(!legacy_key.exists());
+            std::fs::write(&legacy_key, b"old key").unwrap();
+            assert_eq!(key_source.secret().unwrap(), b"old key");
+            std::fs::remove_file(&legacy_key).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_parse_machine_id() {
+        let reg = "\r\nHKEY_LOCAL_MACHINE\\SOFTWARE\\Microsoft\\Cryptography\r\n    \
+                   MachineGuid    REG_SZ    3f1c2a4e-0b6d-4e8a-9c57-1d2e3f405162\r\n\r\n";
+        assert_eq!(
+            parse_machine_guid(reg).as_deref(),
+            Some("3f1c2a4e-0b6d-4e8a-9c57-1d2e3f405162")
+        );
+        assert_eq!(
+            parse_machine_guid("ERROR: The system was unable to find"),
+            None
+        );
+        let ioreg = "+-o J314sAP  <class IOPlatformExpertDevice>\n    {\n      \
+                     \"IOPlatformSerialNumber\" = \"C02XXXXXXXXX\"\n      \
+                     \"IOPlatformUUID\" = \"5D2A3B1C-7E8F-4A6B-9C0D-1E2F3A4B5C6D\"\n    }\n";
+        assert_eq!(
+            parse_platform_uuid(ioreg).as_deref(),
+            Some("5D2A3B1C-7E8F-4A6B-9C0D-1E2F3A4B5C6D")
+        );
+        assert_eq!(parse_platform_uuid(""), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_write_private() {
+        use std::os::unix::fs::PermissionsExt;
+        let path = std::env::temp_dir().join(format!("todo-station-{}.key", std::process::id()));
+        std::fs::write(&path, b"old").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        write_private(&path, b"secret").unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(std::fs::read(&path).unwrap(), b"secret");
+        assert!(!path
+            .with_file_name(format!("todo-station-{}.key.tmp", std::process::id()))
+            .exists());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
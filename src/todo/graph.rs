@@ -0,0 +1,721 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+
+use super::timezone::{parse_time_zone, system_time_zone, to_local};
+use super::token::{cache_path, key_source, Account};
+use super::{CalendarEvent, CalendarSource, Response, ShowAs};
+use crate::config::{get_client, OutlookCalendarConfig, OutlookSourceConfig, Rgb, TodoConfig};
+
+// Items per page, Graph defaults to 10
+const PAGE_SIZE: usize = 50;
+// Only the fields used by `CalendarItem`
+const SELECT_FIELDS: &str =
+    "subject,start,end,isAllDay,isCancelled,location,organizer,onlineMeeting,showAs,responseStatus,categories";
+
+/// Outlook calendars, read from Microsoft Graph `calendarview`
+#[derive(Debug, Clone)]
+pub struct GraphSource {
+    name: String,
+    account: Account,
+    // Microsoft Graph base URL, including the API version
+    graph_url: String,
+    calendars: Vec<OutlookCalendarConfig>,
+    max_items: usize,
+    // Read the category colors from Outlook
+    category_colors: bool,
+    // IANA name of the time zone the events are requested in
+    time_zone: String,
+}
+
+/// The configured `time-zone`, or the system one if it's not set or unknown
+fn request_time_zone(todo: &TodoConfig) -> String {
+    match &todo.time_zone {
+        Some(time_zone) if parse_time_zone(time_zone).is_some() => time_zone.clone(),
+        Some(time_zone) => {
+            warn!("Unknown time zone {time_zone} in `[todo]`, using the system time zone");
+            system_time_zone()
+        }
+        None => system_time_zone(),
+    }
+}
+
+impl GraphSource {
+    pub fn new(cfg: &OutlookSourceConfig, todo: &TodoConfig) -> Self {
+        // Only ask for the permissions the calendars need, group calendars need admin consent
+        let mut extra_scopes = vec![];
+        if cfg.calendars.iter().any(|c| c.user.is_some()) {
+            extra_scopes.push("Calendars.Read.Shared");
+        }
+        if cfg.calendars.iter().any(|c| c.group.is_some()) {
+            extra_scopes.push("Group.Read.All");
+        }
+        if cfg.category_colors {
+            extra_scopes.push("MailboxSettings.Read");
+        }
+        Self {
+            name: cfg.name.clone(),
+            account: Account::new(
+                &cfg.name,
+                &todo.login_url,
+                cfg.app_id.as_ref().unwrap_or(&todo.app_id),
+                cfg.tenant.as_ref().unwrap_or(&todo.tenant),
+                &cache_path(&cfg.name, cfg.token_cache.as_deref()),
+                &key_source(todo),
+                &extra_scopes,
+            ),
+            graph_url: todo.graph_url.trim_end_matches('/').to_string(),
+            calendars: cfg.calendars.clone(),
+            max_items: cfg.max_items,
+            category_colors: cfg.category_colors,
+            time_zone: request_time_zone(todo),
+        }
+    }
+
+    pub fn account(&self) -> &Account {
+        &self.account
+    }
+
+    pub async fn keep_token_fresh(&self) {
+        self.account.keep_token_fresh().await
+    }
+
+    /// The calendars of the account and of the users in the config
+    pub async fn list_calendars(&self) -> anyhow::Result<Vec<(String, CalendarInfo)>> {
+        let token = self.account.get_token().await?;
+        let client = get_client();
+        let mut owners = vec!["/me".to_string()];
+        for calendar in &self.calendars {
+            let owner = owner_path(calendar);
+            if calendar.group.is_none() && !owners.contains(&owner) {
+                owners.push(owner);
+            }
+        }
+        let mut result = vec![];
+        for owner in owners {
+            for calendar in get_calendars(&client, &self.graph_url, &token, &owner).await? {
+                result.push((owner.clone(), calendar));
+            }
+        }
+        Ok(result)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CalendarInfo {
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub is_default_calendar: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Calendars {
+    value: Vec<CalendarInfo>,
+    #[serde(rename = "@odata.nextLink")]
+    next_link: Option<String>,
+}
+
+/// Path of the owner of the calendar, relative to the Graph base URL
+fn owner_path(cfg: &OutlookCalendarConfig) -> String {
+    match (&cfg.group, &cfg.user) {
+        (Some(group), _) => format!("/groups/{group}"),
+        (None, Some(user)) => format!("/users/{user}"),
+        (None, None) => "/me".to_string(),
+    }
+}
+
+async fn get_calendars(
+    client: &reqwest::Client,
+    base_url: &str,
+    token: &str,
+    owner: &str,
+) -> anyhow::Result<Vec<CalendarInfo>> {
+    let mut req = client
+        .get(format!("{base_url}{owner}/calendars"))
+        .query(&[("$select", "id,name,isDefaultCalendar"), ("$top", "100")]);
+    let mut calendars = vec![];
+    loop {
+        // The next link already contains all the query parameters
+        let mut page: Calendars = req
+            .bearer_auth(token)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        calendars.append(&mut page.value);
+        match page.next_link {
+            Some(next_link) => req = client.get(next_link),
+            None => break,
+        }
+    }
+    Ok(calendars)
+}
+
+/// Path of the calendar view of the calendar, calendars given by name are looked up first
+async fn calendar_view_path(
+    client: &reqwest::Client,
+    base_url: &str,
+    token: &str,
+    cfg: &OutlookCalendarConfig,
+) -> anyhow::Result<String> {
+    let owner = owner_path(cfg);
+    let calendar = match &cfg.calendar {
+        // A group only has one calendar
+        Some(calendar) if cfg.group.is_none() => calendar,
+        _ => return Ok(format!("{owner}/calendarview")),
+    };
+    let calendars = get_calendars(client, base_url, token, &owner).await?;
+    let found = calendars
+        .iter()
+        .find(|c| c.id == *calendar)
+        .or_else(|| calendars.iter().find(|c| c.name == *calendar))
+        .ok_or(anyhow::anyhow!("Calendar {calendar} not found in {owner}"))?;
+    Ok(format!("{owner}/calendars/{}/calendarview", found.id))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CalendarItem {
+    subject: String,
+    start: TimeWithZone,
+    end: TimeWithZone,
+    is_all_day: bool,
+    is_cancelled: bool,
+    #[serde(default)]
+    location: Option<Location>,
+    #[serde(default)]
+    organizer: Option<Recipient>,
+    #[serde(default)]
+    online_meeting: Option<OnlineMeetingInfo>,
+    #[serde(default)]
+    show_as: Option<String>,
+    #[serde(default)]
+    response_status: Option<ResponseStatus>,
+    #[serde(default)]
+    categories: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Location {
+    #[serde(default)]
+    display_name: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Recipient {
+    email_address: EmailAddress,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct EmailAddress {
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    address: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OnlineMeetingInfo {
+    #[serde(default)]
+    join_url: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ResponseStatus {
+    response: String,
+}
+
+fn parse_show_as(show_as: &str) -> ShowAs {
+    match show_as {
+        "free" => ShowAs::Free,
+        "tentative" => ShowAs::Tentative,
+        "oof" => ShowAs::OutOfOffice,
+        "workingElsewhere" => ShowAs::WorkingElsewhere,
+        _ => ShowAs::Busy,
+    }
+}
+
+fn parse_response(response: &str) -> Response {
+    match response {
+        "accepted" => Response::Accepted,
+        "tentativelyAccepted" => Response::Tentative,
+        "declined" => Response::Declined,
+        "notResponded" => Response::NotResponded,
+        // "none" and "organizer"
+        _ => Response::None,
+    }
+}
+
+impl From<CalendarItem> for CalendarEvent {
+    fn from(val: CalendarItem) -> Self {
+        // The time zone is the one in the `Prefer` header if Graph accepted it, UTC otherwise
+        CalendarEvent {
+            subject: val.subject,
+            start: to_local(val.start.date_time, &val.start.time_zone),
+            end: to_local(val.end.date_time, &val.end.time_zone),
+            is_all_day: val.is_all_day,
+            is_cancelled: val.is_cancelled,
+            location: val.location.map(|l| l.display_name).unwrap_or_default(),
+            organizer: val
+                .organizer
+                .and_then(|o| o.email_address.name.or(o.email_address.address))
+                .unwrap_or_default(),
+            online_meeting_url: val.online_meeting.and_then(|m| m.join_url),
+            show_as: val
+                .show_as
+                .as_deref()
+                .map(parse_show_as)
+                .unwrap_or_default(),
+            response: val
+                .response_status
+                .map(|r| parse_response(&r.response))
+                .unwrap_or_default(),
+            categories: val.categories,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CalendarItems {
+    value: Vec<CalendarItem>,
+    #[serde(rename = "@odata.nextLink")]
+    next_link: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TimeWithZone {
+    date_time: chrono::NaiveDateTime,
+    time_zone: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OutlookCategory {
+    display_name: String,
+    color: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct OutlookCategories {
+    value: Vec<OutlookCategory>,
+}
+
+/// Approximate the Outlook preset colors, `none` and unknown presets have no color
+fn preset_color(preset: &str) -> Option<Rgb> {
+    let rgb = match preset {
+        "preset0" => [0xe7, 0x48, 0x56],  // Red
+        "preset1" => [0xf7, 0x63, 0x0c],  // Orange
+        "preset2" => [0xab, 0x6e, 0x38],  // Brown
+        "preset3" => [0xff, 0xc8, 0x3d],  // Yellow
+        "preset4" => [0x4c, 0xaf, 0x50],  // Green
+        "preset5" => [0x00, 0xb7, 0xc3],  // Teal
+        "preset6" => [0x8e, 0x8c, 0x2f],  // Olive
+        "preset7" => [0x00, 0x78, 0xd4],  // Blue
+        "preset8" => [0x88, 0x64, 0xb8],  // Purple
+        "preset9" => [0xc2, 0x39, 0xb3],  // Cranberry
+        "preset10" => [0x8a, 0x9b, 0xa6], // Steel
+        "preset11" => [0x4a, 0x54, 0x59], // DarkSteel
+        "preset12" => [0xa0, 0xa0, 0xa0], // Gray
+        "preset13" => [0x5c, 0x5c, 0x5c], // DarkGray
+        "preset14" => [0x20, 0x20, 0x20], // Black
+        "preset15" => [0xa4, 0x26, 0x2c], // DarkRed
+        "preset16" => [0xca, 0x50, 0x10], // DarkOrange
+        "preset17" => [0x6d, 0x3e, 0x1e], // DarkBrown
+        "preset18" => [0xc1, 0x9c, 0x00], // DarkYellow
+        "preset19" => [0x10, 0x7c, 0x10], // DarkGreen
+        "preset20" => [0x03, 0x83, 0x87], // DarkTeal
+        "preset21" => [0x5c, 0x5a, 0x10], // DarkOlive
+        "preset22" => [0x00, 0x4e, 0x8c], // DarkBlue
+        "preset23" => [0x5c, 0x2e, 0x91], // DarkPurple
+        "preset24" => [0x8a, 0x1f, 0x5e], // DarkCranberry
+        _ => return None,
+    };
+    Some(rgb)
+}
+
+/// Get the colors of the user's Outlook categories, categories without a color are left out
+async fn get_category_colors(
+    client: &reqwest::Client,
+    base_url: &str,
+    token: &str,
+) -> anyhow::Result<HashMap<String, Rgb>> {
+    let categories: OutlookCategories = client
+        .get(format!("{base_url}/me/outlook/masterCategories"))
+        .bearer_auth(token)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    Ok(categories
+        .value
+        .into_iter()
+        .filter_map(|category| Some((category.display_name, preset_color(&category.color)?)))
+        .collect())
+}
+
+/// Get the calendar view in `[start, end)`, following `@odata.nextLink` until all pages
+/// are read or `max_items` is reached
+async fn get_calendar_view(
+    client: &reqwest::Client,
+    url: &str,
+    token: &str,
+    time_zone: &str,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    max_items: usize,
+) -> anyhow::Result<Vec<CalendarItem>> {
+    let prefer = format!("outlook.timezone=\"{time_zone}\"");
+    let start = start.format("%Y-%m-%dT%H:%M:%S%.fZ").to_string();
+    let end = end.format("%Y-%m-%dT%H:%M:%S%.fZ").to_string();
+    let top = PAGE_SIZE.min(max_items).to_string();
+    let mut req = client.get(url).query(&[
+        ("startDateTime", start.as_str()),
+        ("endDateTime", end.as_str()),
+        ("$top", top.as_str()),
+        ("$select", SELECT_FIELDS),
+        ("$orderby", "start/dateTime"),
+    ]);
+    let mut items = vec![];
+    loop {
+        // The next link already contains all the query parameters
+        let resp = req
+            .bearer_auth(token)
+            .header("Prefer", &prefer)
+            .send()
+            .await?
+            .error_for_status()?;
+        debug!("Got calendar view page from {}", resp.url());
+        let body = resp.text().await?;
+        let mut page: CalendarItems = serde_json::from_str(&body)?;
+        items.append(&mut page.value);
+        if items.len() >= max_items {
+            if items.len() > max_items || page.next_link.is_some() {
+                warn!("Calendar view has more than {max_items} items, the rest are dropped");
+            }
+            items.truncate(max_items);
+            break;
+        }
+        match page.next_link {
+            Some(next_link) => req = client.get(next_link),
+            None => break,
+        }
+    }
+    Ok(items)
+}
+
+impl CalendarSource for GraphSource {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn get_events(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> anyhow::Result<Vec<CalendarEvent>> {
+        let token = self.account.get_token().await?;
+        let client = get_client();
+        debug!("Requesting todo list from {}", self.graph_url);
+        let calendars = if self.calendars.is_empty() {
+            vec![OutlookCalendarConfig::default()]
+        } else {
+            self.calendars.clone()
+        };
+        let get_items = async {
+            let mut items = vec![];
+            for calendar in &calendars {
+                let path = calendar_view_path(&client, &self.graph_url, &token, calendar).await?;
+                let mut view = get_calendar_view(
+                    &client,
+                    &format!("{}{path}", self.graph_url),
+                    &token,
+                    &self.time_zone,
+                    start,
+                    end,
+                    self.max_items,
+                )
+                .await?;
+                items.append(&mut view);
+            }
+            anyhow::Ok(items)
+        };
+        let get_colors = async {
+            if self.category_colors {
+                get_category_colors(&client, &self.graph_url, &token).await
+            } else {
+                Ok(HashMap::new())
+            }
+        };
+        let (items, colors) = futures::future::join(get_items, get_colors).await;
+        let items = items?;
+        // Needs `MailboxSettings.Read`, the events are still shown without the colors
+        let colors = colors.unwrap_or_else(|e| {
+            warn!("Failed to get Outlook categories, error: {e}");
+            HashMap::new()
+        });
+        info!("{} items retrieved from {}", items.len(), self.name);
+        Ok(items
+            .into_iter()
+            .map(|item| {
+                let mut event: CalendarEvent = item.into();
+                event.color = event
+                    .categories
+                    .iter()
+                    .find_map(|category| colors.get(category).copied());
+                event
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Days;
+    use serde_json::json;
+    use wiremock::matchers::{header, method, path, query_param, query_param_is_missing};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use super::*;
+
+    fn item(subject: &str) -> serde_json::Value {
+        json!({
+            "subject": subject,
+            "start": { "dateTime": "2024-06-03T01:00:00.0000000", "timeZone": "UTC" },
+            "end": { "dateTime": "2024-06-03T02:00:00.0000000", "timeZone": "UTC" },
+            "isAllDay": false,
+            "isCancelled": false,
+        })
+    }
+
+    async fn mock_pages(server: &MockServer) {
+        Mock::given(method("GET"))
+            .and(path("/me/calendarview"))
+            .and(header("Authorization", "Bearer TOKEN"))
+            .and(header("Prefer", "outlook.timezone=\"UTC\""))
+            .and(query_param("$select", SELECT_FIELDS))
+            .and(query_param_is_missing("$skip"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "value": [item("a"), item("b")],
+                "@odata.nextLink": format!("{}/me/calendarview?$skip=2", server.uri()),
+            })))
+            .mount(server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/me/calendarview"))
+            .and(header("Authorization", "Bearer TOKEN"))
+            .and(query_param("$skip", "2"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "value": [item("c")],
+            })))
+            .mount(server)
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_follow_next_link() {
+        let server = MockServer::start().await;
+        mock_pages(&server).await;
+        let now = Utc::now();
+        let items = get_calendar_view(
+            &get_client(),
+            &format!("{}/me/calendarview", server.uri()),
+            "TOKEN",
+            "UTC",
+            now,
+            now,
+            100,
+        )
+        .await
+        .unwrap();
+        let subjects: Vec<_> = items.iter().map(|i| i.subject.as_str()).collect();
+        assert_eq!(subjects, ["a", "b", "c"]);
+    }
+
+    #[tokio::test]
+    async fn test_max_items() {
+        let server = MockServer::start().await;
+        mock_pages(&server).await;
+        let now = Utc::now();
+        let items = get_calendar_view(
+            &get_client(),
+            &format!("{}/me/calendarview", server.uri()),
+            "TOKEN",
+            "UTC",
+            now,
+            now,
+            2,
+        )
+        .await
+        .unwrap();
+        assert_eq!(items.len(), 2);
+        // The second page is never requested
+        assert_eq!(server.received_requests().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_error_status() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(401))
+            .mount(&server)
+            .await;
+        let now = Utc::now();
+        let result = get_calendar_view(
+            &get_client(),
+            &format!("{}/me/calendarview", server.uri()),
+            "TOKEN",
+            "UTC",
+            now,
+            now,
+            100,
+        )
+        .await;
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_event_details() {
+        let details: CalendarItem = serde_json::from_value(json!({
+            "subject": "Sync",
+            "start": { "dateTime": "2024-06-03T01:00:00.0000000", "timeZone": "UTC" },
+            "end": { "dateTime": "2024-06-03T02:00:00.0000000", "timeZone": "UTC" },
+            "isAllDay": false,
+            "isCancelled": false,
+            "location": { "displayName": "Room 101" },
+            "organizer": { "emailAddress": { "name": "Alice", "address": "alice@example.com" } },
+            "onlineMeeting": { "joinUrl": "https://teams.microsoft.com/l/meetup-join/1" },
+            "showAs": "tentative",
+            "responseStatus": { "response": "tentativelyAccepted", "time": "0001-01-01T00:00:00Z" },
+        }))
+        .unwrap();
+        let event: CalendarEvent = details.into();
+        assert_eq!(event.location, "Room 101");
+        assert_eq!(event.organizer, "Alice");
+        assert!(event.online_meeting_url.is_some());
+        assert_eq!(event.show_as, ShowAs::Tentative);
+        assert_eq!(event.response, Response::Tentative);
+
+        // Events without the optional fields, e.g. `onlineMeeting` is null for normal meetings
+        let mut value = item("a");
+        value["onlineMeeting"] = serde_json::Value::Null;
+        let event: CalendarEvent = serde_json::from_value::<CalendarItem>(value)
+            .unwrap()
+            .into();
+        assert_eq!(event.location, "");
+        assert_eq!(event.online_meeting_url, None);
+        assert_eq!(event.show_as, ShowAs::Busy);
+        assert_eq!(event.response, Response::None);
+    }
+
+    #[tokio::test]
+    async fn test_category_colors() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/me/outlook/masterCategories"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "value": [
+                    { "id": "1", "displayName": "Customer", "color": "preset0" },
+                    { "id": "2", "displayName": "Internal", "color": "none" },
+                ],
+            })))
+            .mount(&server)
+            .await;
+        let colors = get_category_colors(&get_client(), &server.uri(), "TOKEN")
+            .await
+            .unwrap();
+        assert_eq!(colors.get("Customer"), preset_color("preset0").as_ref());
+        assert!(!colors.contains_key("Internal"));
+    }
+
+    #[tokio::test]
+    async fn test_calendar_view_path() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/users/room101@example.com/calendars"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "value": [
+                    { "id": "AAMkAD1", "name": "Calendar", "isDefaultCalendar": true },
+                ],
+                "@odata.nextLink": format!(
+                    "{}/users/room101@example.com/calendars?$skiptoken=page2",
+                    server.uri()
+                ),
+            })))
+            .mount(&server)
+            .await;
+        // Calendars on the later pages are found too
+        Mock::given(method("GET"))
+            .and(path("/users/room101@example.com/calendars"))
+            .and(query_param("$skiptoken", "page2"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "value": [{ "id": "AAMkAD2", "name": "Bookings" }],
+            })))
+            .with_priority(1)
+            .mount(&server)
+            .await;
+        let view_path = |cfg: OutlookCalendarConfig| {
+            let server = &server;
+            async move { calendar_view_path(&get_client(), &server.uri(), "TOKEN", &cfg).await }
+        };
+        let room = |calendar: &str| OutlookCalendarConfig {
+            calendar: Some(calendar.to_string()),
+            user: Some("room101@example.com".to_string()),
+            group: None,
+        };
+        assert_eq!(
+            view_path(OutlookCalendarConfig::default()).await.unwrap(),
+            "/me/calendarview"
+        );
+        assert_eq!(
+            view_path(room("Bookings")).await.unwrap(),
+            "/users/room101@example.com/calendars/AAMkAD2/calendarview"
+        );
+        assert_eq!(
+            view_path(room("AAMkAD1")).await.unwrap(),
+            "/users/room101@example.com/calendars/AAMkAD1/calendarview"
+        );
+        assert!(view_path(room("Missing")).await.is_err());
+        let group = OutlookCalendarConfig {
+            calendar: Some("Calendar".to_string()),
+            user: None,
+            group: Some("02bd9fd6".to_string()),
+        };
+        assert_eq!(
+            view_path(group).await.unwrap(),
+            "/groups/02bd9fd6/calendarview"
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "Needs interactive login"]
+    async fn test_get_events() {
+        let app_id = std::env::var("AAD_APP_ID").unwrap().to_string();
+        let tenant = std::env::var("AAD_TENANT").unwrap_or("consumers".to_string());
+        let todo = TodoConfig {
+            app_id,
+            tenant,
+            ..Default::default()
+        };
+        let source = GraphSource::new(&OutlookSourceConfig::default(), &todo);
+        let now = Utc::now();
+        let events = source
+            .get_events(now, now.checked_add_days(Days::new(7)).unwrap())
+            .await
+            .unwrap();
+        println!("{events:?}");
+    }
+}
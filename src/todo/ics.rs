@@ -0,0 +1,623 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::BufReader,
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use ical::{parser::ical::component::IcalEvent, property::Property, IcalParser};
+use log::{debug, info, warn};
+use rrule::{RRule, RRuleSet, Tz, Unvalidated};
+
+use super::timezone::parse_time_zone;
+use super::{CalendarEvent, CalendarSource, ShowAs};
+use crate::config::IcsSourceConfig;
+
+// Hard limit of the recurrences of a single event in the window, prevents runaway rules
+const MAX_RECURRENCES: u16 = 1000;
+
+/// Calendar read from local iCalendar files
+#[derive(Debug, Clone)]
+pub struct IcsSource {
+    name: String,
+    paths: Vec<PathBuf>,
+    // Parsed files, re-read only when the modification time changes
+    cache: Arc<Mutex<HashMap<PathBuf, CachedFile>>>,
+}
+
+#[derive(Debug, Clone)]
+struct CachedFile {
+    modified: SystemTime,
+    events: Vec<VEvent>,
+}
+
+impl IcsSource {
+    pub fn new(cfg: &IcsSourceConfig) -> Self {
+        Self {
+            name: cfg.name.clone(),
+            paths: cfg.paths.clone(),
+            cache: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    async fn load(&self, path: &PathBuf) -> anyhow::Result<Vec<VEvent>> {
+        let modified = tokio::fs::metadata(path).await?.modified()?;
+        if let Some(cached) = self.cache.lock().unwrap().get(path) {
+            if cached.modified == modified {
+                debug!("{} is not changed, using cached events", path.display());
+                return Ok(cached.events.clone());
+            }
+        }
+        info!("Reading calendar file {}", path.display());
+        let content = tokio::fs::read_to_string(path).await?;
+        let events = parse_calendar(&content)?;
+        self.cache.lock().unwrap().insert(
+            path.clone(),
+            CachedFile {
+                modified,
+                events: events.clone(),
+            },
+        );
+        Ok(events)
+    }
+}
+
+impl CalendarSource for IcsSource {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn get_events(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> anyhow::Result<Vec<CalendarEvent>> {
+        let mut events = vec![];
+        let mut loaded = 0;
+        let mut last_error = None;
+        for path in &self.paths {
+            match self.load(path).await {
+                Ok(mut file_events) => {
+                    loaded += 1;
+                    events.append(&mut file_events)
+                }
+                Err(e) => {
+                    warn!("Failed to read {}, error: {e}", path.display());
+                    last_error = Some(e);
+                }
+            }
+        }
+        // Only fail when no file could be read, a broken file should not hide the others
+        if let Some(e) = last_error {
+            if loaded == 0 {
+                return Err(e);
+            }
+        }
+        let events = expand_events(&events, start, end);
+        info!("{} items retrieved from {}", events.len(), self.name);
+        Ok(events)
+    }
+}
+
+/// A VEVENT with its recurrence rules not yet expanded
+#[derive(Debug, Clone)]
+pub struct VEvent {
+    uid: String,
+    summary: String,
+    start: DateTime<Tz>,
+    duration: chrono::Duration,
+    is_all_day: bool,
+    is_cancelled: bool,
+    location: String,
+    organizer: String,
+    online_meeting_url: Option<String>,
+    show_as: ShowAs,
+    categories: Vec<String>,
+    rrules: Vec<String>,
+    rdates: Vec<DateTime<Tz>>,
+    exdates: Vec<DateTime<Tz>>,
+    recurrence_id: Option<DateTime<Tz>>,
+}
+
+/// Parse all VEVENTs in an iCalendar document
+pub fn parse_calendar(content: &str) -> anyhow::Result<Vec<VEvent>> {
+    let mut events = vec![];
+    for calendar in IcalParser::new(BufReader::new(content.as_bytes())) {
+        for event in calendar?.events {
+            match parse_event(&event) {
+                Ok(event) => events.push(event),
+                // Skip the broken event instead of the whole calendar
+                Err(e) => warn!("Failed to parse event, error: {e}"),
+            }
+        }
+    }
+    Ok(events)
+}
+
+fn get_param<'a>(property: &'a Property, name: &str) -> Option<&'a str> {
+    property
+        .params
+        .as_ref()?
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .and_then(|(_, values)| values.first())
+        .map(|value| value.as_str())
+}
+
+fn parse_event(event: &IcalEvent) -> anyhow::Result<VEvent> {
+    let mut uid = String::new();
+    let mut summary = String::new();
+    let mut start = None;
+    let mut end = None;
+    let mut duration = None;
+    let mut is_cancelled = false;
+    let mut location = String::new();
+    let mut organizer = String::new();
+    let mut online_meeting_url = None;
+    let mut show_as = ShowAs::Busy;
+    let mut categories = vec![];
+    let mut rrules = vec![];
+    let mut rdates = vec![];
+    let mut exdates = vec![];
+    let mut recurrence_id = None;
+    for property in &event.properties {
+        let Some(value) = property.value.as_deref() else {
+            continue;
+        };
+        match property.name.to_ascii_uppercase().as_str() {
+            "UID" => uid = value.to_string(),
+            "SUMMARY" => summary = unescape_text(value),
+            "DTSTART" => start = Some(parse_date_time(property, value)?),
+            "DTEND" => end = Some(parse_date_time(property, value)?),
+            "DURATION" => duration = Some(parse_duration(value)?),
+            "STATUS" => {
+                is_cancelled = value.eq_ignore_ascii_case("CANCELLED");
+                if value.eq_ignore_ascii_case("TENTATIVE") {
+                    show_as = ShowAs::Tentative;
+                }
+            }
+            "TRANSP" if value.eq_ignore_ascii_case("TRANSPARENT") => show_as = ShowAs::Free,
+            "LOCATION" => location = unescape_text(value),
+            "CATEGORIES" => categories.extend(
+                split_unescaped(value, ',')
+                    .into_iter()
+                    .map(|category| unescape_text(category.trim()))
+                    .filter(|category| !category.is_empty()),
+            ),
+            "ORGANIZER" => {
+                organizer = match get_param(property, "CN") {
+                    Some(name) => name.trim_matches('"').to_string(),
+                    None => value.trim_start_matches("mailto:").to_string(),
+                }
+            }
+            // Teams and Google Meet put the meeting link in their own properties
+            "X-MICROSOFT-SKYPETEAMSMEETINGURL" | "X-GOOGLE-CONFERENCE" => {
+                online_meeting_url = Some(value.to_string())
+            }
+            "RRULE" => rrules.push(value.to_string()),
+            "RDATE" => {
+                for value in value.split(',') {
+                    rdates.push(parse_date_time(property, value)?.0);
+                }
+            }
+            "EXDATE" => {
+                for value in value.split(',') {
+                    exdates.push(parse_date_time(property, value)?.0);
+                }
+            }
+            "RECURRENCE-ID" => recurrence_id = Some(parse_date_time(property, value)?.0),
+            _ => {}
+        }
+    }
+    let (start, is_all_day) = start.ok_or(anyhow::anyhow!("Event {uid} has no DTSTART"))?;
+    // RFC 5545: without DTEND or DURATION, an all-day event lasts one day, otherwise it ends at DTSTART
+    let duration = match (end, duration) {
+        (Some((end, _)), _) => end - start,
+        (None, Some(duration)) => duration,
+        (None, None) if is_all_day => chrono::Duration::days(1),
+        (None, None) => chrono::Duration::zero(),
+    };
+    Ok(VEvent {
+        uid,
+        summary,
+        start,
+        duration,
+        is_all_day,
+        is_cancelled,
+        location,
+        organizer,
+        online_meeting_url,
+        show_as,
+        categories,
+        rrules,
+        rdates,
+        exdates,
+        recurrence_id,
+    })
+}
+
+/// Unescape a TEXT value in one pass, so `\\n` is a backslash followed by `n`
+fn unescape_text(value: &str) -> String {
+    let mut result = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n' | 'N') => result.push('\n'),
+            Some(c @ ('\\' | ',' | ';')) => result.push(c),
+            // Not a valid escape, keep it as it is
+            Some(c) => {
+                result.push('\\');
+                result.push(c);
+            }
+            None => result.push('\\'),
+        }
+    }
+    result
+}
+
+/// Split a list of TEXT values on the separators that are not escaped
+fn split_unescaped(value: &str, separator: char) -> Vec<&str> {
+    let mut parts = vec![];
+    let mut begin = 0;
+    let mut escaped = false;
+    for (i, c) in value.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == separator {
+            parts.push(&value[begin..i]);
+            begin = i + c.len_utf8();
+        }
+    }
+    parts.push(&value[begin..]);
+    parts
+}
+
+/// Map a TZID to a time zone, unknown zones fall back to local time
+fn parse_tz(tzid: &str) -> Tz {
+    match parse_time_zone(tzid) {
+        Some(tz) => Tz::Tz(tz),
+        None => {
+            warn!("Unknown time zone {tzid}, using local time zone");
+            Tz::LOCAL
+        }
+    }
+}
+
+/// Parse a DATE or DATE-TIME value, returns the time and whether it is a date only
+fn parse_date_time(property: &Property, value: &str) -> anyhow::Result<(DateTime<Tz>, bool)> {
+    let value = value.trim();
+    if get_param(property, "VALUE").is_some_and(|v| v.eq_ignore_ascii_case("DATE"))
+        || value.len() == 8
+    {
+        let date = NaiveDate::parse_from_str(value, "%Y%m%d")?;
+        return Ok((to_tz(&Tz::LOCAL, date.and_hms_opt(0, 0, 0).unwrap())?, true));
+    }
+    if let Some(value) = value.strip_suffix('Z') {
+        let time = NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S")?;
+        return Ok((time.and_utc().with_timezone(&Tz::UTC), false));
+    }
+    let time = NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S")?;
+    // Floating time is in the local time zone
+    let tz = get_param(property, "TZID").map_or(Tz::LOCAL, parse_tz);
+    Ok((to_tz(&tz, time)?, false))
+}
+
+fn to_tz(tz: &Tz, time: NaiveDateTime) -> anyhow::Result<DateTime<Tz>> {
+    // Times skipped by a DST change are moved forward by an hour
+    tz.from_local_datetime(&time)
+        .earliest()
+        .or_else(|| {
+            tz.from_local_datetime(&(time + chrono::Duration::hours(1)))
+                .earliest()
+        })
+        .ok_or(anyhow::anyhow!("Invalid local time {time}"))
+}
+
+/// Parse a DURATION value such as `PT1H30M` or `-P1W`
+fn parse_duration(value: &str) -> anyhow::Result<chrono::Duration> {
+    let invalid = || anyhow::anyhow!("Invalid duration {value}");
+    let (negative, rest) = match value.trim().strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, value.trim().trim_start_matches('+')),
+    };
+    let rest = rest.strip_prefix('P').ok_or_else(invalid)?;
+    let mut duration = chrono::Duration::zero();
+    let mut number = String::new();
+    let mut in_time = false;
+    for c in rest.chars() {
+        match c {
+            '0'..='9' => number.push(c),
+            'T' => in_time = true,
+            _ => {
+                let n: i64 = number.parse().map_err(|_| invalid())?;
+                number.clear();
+                duration += match (c, in_time) {
+                    ('W', false) => chrono::Duration::weeks(n),
+                    ('D', false) => chrono::Duration::days(n),
+                    ('H', true) => chrono::Duration::hours(n),
+                    ('M', true) => chrono::Duration::minutes(n),
+                    ('S', true) => chrono::Duration::seconds(n),
+                    _ => return Err(invalid()),
+                };
+            }
+        }
+    }
+    Ok(if negative { -duration } else { duration })
+}
+
+fn recurrences(event: &VEvent, start: DateTime<Utc>, end: DateTime<Utc>) -> Vec<DateTime<Tz>> {
+    if event.rrules.is_empty() && event.rdates.is_empty() {
+        return vec![event.start];
+    }
+    let mut set = RRuleSet::new(event.start);
+    for rule in &event.rrules {
+        let rule = match RRule::<Unvalidated>::from_str(rule) {
+            Ok(rule) => rule,
+            Err(e) => {
+                warn!("Invalid RRULE {rule} in {}, error: {e}", event.uid);
+                continue;
+            }
+        };
+        // RFC 5545 requires UNTIL in UTC when DTSTART has a time zone, but not every exporter follows it
+        let rule = match rule.get_until().copied() {
+            Some(until) if !event.start.timezone().is_local() => {
+                rule.until(until.with_timezone(&Tz::UTC))
+            }
+            _ => rule,
+        };
+        match rule.validate(event.start) {
+            Ok(rule) => set = set.rrule(rule),
+            Err(e) => warn!("Invalid RRULE in {}, error: {e}", event.uid),
+        }
+    }
+    // DTSTART is always the first instance, even if it does not match the rule
+    let mut rdates = event.rdates.clone();
+    rdates.push(event.start);
+    // Include the instances that started before the window but are still going on
+    let after = (start - event.duration).with_timezone(&Tz::UTC);
+    let before = end.with_timezone(&Tz::UTC);
+    let result = set
+        .set_rdates(rdates)
+        .set_exdates(event.exdates.clone())
+        .after(after)
+        .before(before)
+        .all(MAX_RECURRENCES);
+    if result.limited {
+        warn!("Too many recurrences of {}, some are dropped", event.uid);
+    }
+    let mut dates = result.dates;
+    dates.sort();
+    dates.dedup();
+    dates
+}
+
+/// Expand the recurring events and keep the instances that overlap with `[start, end)`
+pub fn expand_events(
+    events: &[VEvent],
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> Vec<CalendarEvent> {
+    // Instances replaced by a separate VEVENT with RECURRENCE-ID
+    let overridden: HashSet<(&str, DateTime<Utc>)> = events
+        .iter()
+        .filter_map(|event| {
+            event
+                .recurrence_id
+                .map(|id| (event.uid.as_str(), id.with_timezone(&Utc)))
+        })
+        .collect();
+    let mut result = vec![];
+    for event in events {
+        let instances = if event.recurrence_id.is_some() {
+            vec![event.start]
+        } else {
+            recurrences(event, start, end)
+        };
+        for instance in instances {
+            let instance_start = instance.with_timezone(&Utc);
+            if event.recurrence_id.is_none()
+                && overridden.contains(&(event.uid.as_str(), instance_start))
+            {
+                continue;
+            }
+            let instance_end = instance_start + event.duration;
+            let overlaps = if event.duration.is_zero() {
+                instance_start >= start && instance_start < end
+            } else {
+                instance_start < end && instance_end > start
+            };
+            if overlaps {
+                result.push(CalendarEvent {
+                    subject: event.summary.clone(),
+                    start: instance_start.with_timezone(&Local),
+                    end: instance_end.with_timezone(&Local),
+                    is_all_day: event.is_all_day,
+                    is_cancelled: event.is_cancelled,
+                    location: event.location.clone(),
+                    organizer: event.organizer.clone(),
+                    online_meeting_url: event.online_meeting_url.clone(),
+                    show_as: event.show_as,
+                    categories: event.categories.clone(),
+                    ..Default::default()
+                });
+            }
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CALENDAR: &str = "BEGIN:VCALENDAR\r
+VERSION:2.0\r
+BEGIN:VEVENT\r
+UID:standup\r
+SUMMARY:Standup\r
+DTSTART;TZID=Asia/Shanghai:20240603T093000\r
+DTEND;TZID=Asia/Shanghai:20240603T094500\r
+RRULE:FREQ=DAILY;BYDAY=MO,TU,WE,TH,FR\r
+EXDATE;TZID=Asia/Shanghai:20240605T093000\r
+RDATE;TZID=Asia/Shanghai:20240608T100000\r
+END:VEVENT\r
+BEGIN:VEVENT\r
+UID:standup\r
+RECURRENCE-ID;TZID=Asia/Shanghai:20240606T093000\r
+SUMMARY:Standup (moved)\r
+DTSTART;TZID=Asia/Shanghai:20240606T110000\r
+DTEND;TZID=Asia/Shanghai:20240606T111500\r
+END:VEVENT\r
+BEGIN:VEVENT\r
+UID:holiday\r
+SUMMARY:Holiday\r
+DTSTART;VALUE=DATE:20240610\r
+END:VEVENT\r
+END:VCALENDAR\r
+";
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(
+            parse_duration("PT1H30M").unwrap(),
+            chrono::Duration::minutes(90)
+        );
+        assert_eq!(parse_duration("P1W").unwrap(), chrono::Duration::days(7));
+        assert_eq!(parse_duration("-P1D").unwrap(), chrono::Duration::days(-1));
+        assert!(parse_duration("1H").is_err());
+    }
+
+    #[test]
+    fn test_expand_events() {
+        let events = parse_calendar(CALENDAR).unwrap();
+        assert_eq!(events.len(), 3);
+        let mut expanded = expand_events(
+            &events,
+            utc("2024-06-03T00:00:00+08:00"),
+            utc("2024-06-10T00:00:00+08:00"),
+        );
+        expanded.sort_by_key(|event| event.start);
+        // The all-day holiday may fall in the window depending on the local time zone
+        expanded.retain(|event| !event.is_all_day);
+        let starts: Vec<_> = expanded
+            .iter()
+            .map(|event| (event.subject.as_str(), event.start.with_timezone(&Utc)))
+            .collect();
+        assert_eq!(
+            starts,
+            [
+                ("Standup", utc("2024-06-03T09:30:00+08:00")),
+                ("Standup", utc("2024-06-04T09:30:00+08:00")),
+                ("Standup (moved)", utc("2024-06-06T11:00:00+08:00")),
+                ("Standup", utc("2024-06-07T09:30:00+08:00")),
+                ("Standup", utc("2024-06-08T10:00:00+08:00")),
+            ]
+        );
+        assert_eq!(
+            expanded[0].end - expanded[0].start,
+            chrono::Duration::minutes(15)
+        );
+    }
+
+    #[test]
+    fn test_all_day_event() {
+        let events = parse_calendar(CALENDAR).unwrap();
+        let start = Local.with_ymd_and_hms(2024, 6, 10, 0, 0, 0).unwrap();
+        let expanded: Vec<_> = expand_events(
+            &events,
+            start.with_timezone(&Utc),
+            (start + chrono::Duration::days(1)).with_timezone(&Utc),
+        )
+        .into_iter()
+        .filter(|event| event.subject == "Holiday")
+        .collect();
+        assert_eq!(expanded.len(), 1);
+        assert!(expanded[0].is_all_day);
+        assert_eq!(expanded[0].start, start);
+        assert_eq!(
+            expanded[0].end - expanded[0].start,
+            chrono::Duration::days(1)
+        );
+    }
+
+    #[test]
+    fn test_event_details() {
+        let calendar = "BEGIN:VCALENDAR\r
+BEGIN:VEVENT\r
+UID:review\r
+SUMMARY:Review\r
+DTSTART:20240603T040000Z\r
+LOCATION:Room 101\\, 1st floor\r
+ORGANIZER;CN=Alice:mailto:alice@example.com\r
+STATUS:TENTATIVE\r
+CATEGORIES:Customer,Review,Foo\\, Bar\r
+X-MICROSOFT-SKYPETEAMSMEETINGURL:https://teams.microsoft.com/l/meetup-join/1\r
+END:VEVENT\r
+END:VCALENDAR\r
+";
+        let events = parse_calendar(calendar).unwrap();
+        let expanded = expand_events(
+            &events,
+            utc("2024-06-03T00:00:00Z"),
+            utc("2024-06-04T00:00:00Z"),
+        );
+        assert_eq!(expanded[0].location, "Room 101, 1st floor");
+        assert_eq!(expanded[0].organizer, "Alice");
+        assert_eq!(expanded[0].show_as, ShowAs::Tentative);
+        assert_eq!(expanded[0].categories, ["Customer", "Review", "Foo, Bar"]);
+        assert!(expanded[0].online_meeting_url.is_some());
+    }
+
+    #[test]
+    fn test_unescape_text() {
+        assert_eq!(unescape_text("a\\nb\\Nc"), "a\nb\nc");
+        assert_eq!(unescape_text("C:\\\\new"), "C:\\new");
+        assert_eq!(unescape_text("a\\, b\\; c"), "a, b; c");
+        assert_eq!(unescape_text("trailing\\"), "trailing\\");
+        assert_eq!(
+            split_unescaped("a\\,b,c\\\\,d", ','),
+            ["a\\,b", "c\\\\", "d"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_missing_file() {
+        let path = std::env::temp_dir().join(format!("todo-station-{}.ics", std::process::id()));
+        std::fs::write(&path, CALENDAR).unwrap();
+        let source = IcsSource::new(&IcsSourceConfig {
+            name: "ics".to_string(),
+            paths: vec![PathBuf::from("/nonexistent/calendar.ics"), path.clone()],
+        });
+        let events = source
+            .get_events(
+                utc("2024-06-03T00:00:00+08:00"),
+                utc("2024-06-04T00:00:00+08:00"),
+            )
+            .await
+            .unwrap();
+        assert_eq!(events.len(), 1);
+        std::fs::remove_file(&path).unwrap();
+
+        let source = IcsSource::new(&IcsSourceConfig {
+            name: "ics".to_string(),
+            paths: vec![PathBuf::from("/nonexistent/calendar.ics")],
+        });
+        assert!(source
+            .get_events(utc("2024-06-03T00:00:00Z"), utc("2024-06-04T00:00:00Z"))
+            .await
+            .is_err());
+    }
+}
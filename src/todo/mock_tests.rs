@@ -0,0 +1,332 @@
+//! The Outlook source end to end against a mock server: device code sign-in, token refresh
+//! and the calendar view, no Microsoft account needed
+
+use std::path::PathBuf;
+
+use chrono::{Days, Utc};
+use serde_json::json;
+use wiremock::matchers::{body_string_contains, header, method, path, query_param};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+use super::graph::GraphSource;
+use super::CalendarSource;
+use crate::config::{OutlookSourceConfig, TodoConfig};
+
+const TENANT: &str = "consumers";
+
+/// A source using `server` for both the login and Graph, with its own token cache
+fn source(server: &MockServer, test: &str) -> (GraphSource, PathBuf) {
+    let cache =
+        std::env::temp_dir().join(format!("todo-station-{test}-{}.json", std::process::id()));
+    let _ = std::fs::remove_file(&cache);
+    let todo = TodoConfig {
+        app_id: "app".to_string(),
+        tenant: TENANT.to_string(),
+        token_passphrase: Some("test".to_string()),
+        login_url: server.uri(),
+        graph_url: format!("{}/v1.0", server.uri()),
+        time_zone: Some("UTC".to_string()),
+        ..Default::default()
+    };
+    let outlook = OutlookSourceConfig {
+        token_cache: Some(cache.clone()),
+        category_colors: true,
+        ..Default::default()
+    };
+    (GraphSource::new(&outlook, &todo), cache)
+}
+
+fn device_code(code: &str) -> ResponseTemplate {
+    ResponseTemplate::new(200).set_body_json(json!({
+        "device_code": code,
+        "user_code": code.to_uppercase(),
+        "verification_uri": "https://microsoft.com/devicelogin",
+        "expires_in": 900,
+        "interval": 0,
+        "message": format!("Enter {} to sign in", code.to_uppercase()),
+    }))
+}
+
+fn token(access_token: &str, refresh_token: &str, expires_in: u64) -> ResponseTemplate {
+    ResponseTemplate::new(200).set_body_json(json!({
+        "token_type": "Bearer",
+        "scope": "openid offline_access User.Read Calendars.Read MailboxSettings.Read",
+        "expires_in": expires_in,
+        "access_token": access_token,
+        "refresh_token": refresh_token,
+    }))
+}
+
+fn token_error(error: &str) -> ResponseTemplate {
+    ResponseTemplate::new(400).set_body_json(json!({
+        "error": error,
+        "error_description": format!("AADSTS: {error}"),
+    }))
+}
+
+async fn mock_device_code(server: &MockServer, codes: &[&str]) {
+    // Each sign-in gets the next code, the last one is returned from then on
+    for (i, code) in codes.iter().enumerate() {
+        let mock = Mock::given(method("POST"))
+            .and(path(format!("/{TENANT}/oauth2/v2.0/devicecode")))
+            .and(body_string_contains("client_id=app"))
+            .respond_with(device_code(code))
+            .with_priority(i as u8 + 1);
+        let mock = if i + 1 < codes.len() {
+            mock.up_to_n_times(1)
+        } else {
+            mock
+        };
+        mock.mount(server).await;
+    }
+}
+
+/// Poll responses for `code`, `pending` times `authorization_pending` before `response`
+async fn mock_poll(server: &MockServer, code: &str, pending: u64, response: ResponseTemplate) {
+    if pending > 0 {
+        Mock::given(method("POST"))
+            .and(path(format!("/{TENANT}/oauth2/v2.0/token")))
+            .and(body_string_contains(format!("device_code={code}")))
+            .respond_with(token_error("authorization_pending"))
+            .up_to_n_times(pending)
+            .with_priority(1)
+            .mount(server)
+            .await;
+    }
+    Mock::given(method("POST"))
+        .and(path(format!("/{TENANT}/oauth2/v2.0/token")))
+        .and(body_string_contains(format!("device_code={code}")))
+        .respond_with(response)
+        .with_priority(2)
+        .mount(server)
+        .await;
+}
+
+async fn mock_refresh(server: &MockServer, refresh_token: &str, response: ResponseTemplate) {
+    Mock::given(method("POST"))
+        .and(path(format!("/{TENANT}/oauth2/v2.0/token")))
+        .and(body_string_contains("grant_type=refresh_token"))
+        .and(body_string_contains(format!(
+            "refresh_token={refresh_token}"
+        )))
+        .respond_with(response)
+        .mount(server)
+        .await;
+}
+
+fn item(subject: &str, categories: &[&str]) -> serde_json::Value {
+    json!({
+        "subject": subject,
+        "start": { "dateTime": "2024-06-03T01:00:00.0000000", "timeZone": "UTC" },
+        "end": { "dateTime": "2024-06-03T02:00:00.0000000", "timeZone": "UTC" },
+        "isAllDay": false,
+        "isCancelled": false,
+        "categories": categories,
+    })
+}
+
+/// Two pages of the default calendar and the categories, only for `access_token`
+async fn mock_graph(server: &MockServer, access_token: &str) {
+    let auth = format!("Bearer {access_token}");
+    Mock::given(method("GET"))
+        .and(path("/v1.0/me/calendarview"))
+        .and(header("Authorization", auth.as_str()))
+        .and(header("Prefer", "outlook.timezone=\"UTC\""))
+        .and(query_param("$top", "50"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "value": [item("Standup", &["Red category"]), item("Review", &[])],
+            "@odata.nextLink": format!("{}/v1.0/me/calendarview?$skiptoken=page2", server.uri()),
+        })))
+        .mount(server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/v1.0/me/calendarview"))
+        .and(header("Authorization", auth.as_str()))
+        .and(query_param("$skiptoken", "page2"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "value": [item("Retro", &[])],
+        })))
+        .with_priority(1)
+        .mount(server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/v1.0/me/outlook/masterCategories"))
+        .and(header("Authorization", auth.as_str()))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "value": [{ "displayName": "Red category", "color": "preset0" }],
+        })))
+        .mount(server)
+        .await;
+}
+
+async fn subjects(source: &GraphSource) -> anyhow::Result<Vec<String>> {
+    let now = Utc::now();
+    let events = source
+        .get_events(now, now.checked_add_days(Days::new(7)).unwrap())
+        .await?;
+    Ok(events.into_iter().map(|event| event.subject).collect())
+}
+
+async fn requests_to(server: &MockServer, path: &str) -> usize {
+    server
+        .received_requests()
+        .await
+        .unwrap()
+        .iter()
+        .filter(|request| request.url.path() == path)
+        .count()
+}
+
+#[tokio::test]
+async fn test_sign_in_refresh_and_read() {
+    let server = MockServer::start().await;
+    mock_device_code(&server, &["code1"]).await;
+    // Expires within the 30s margin, so the next read refreshes it
+    mock_poll(&server, "code1", 2, token("access1", "refresh1", 10)).await;
+    mock_refresh(&server, "refresh1", token("access2", "refresh2", 3600)).await;
+    mock_graph(&server, "access1").await;
+    mock_graph(&server, "access2").await;
+    let (source, cache) = source(&server, "cycle");
+
+    assert_eq!(
+        subjects(&source).await.unwrap(),
+        ["Standup", "Review", "Retro"]
+    );
+    assert_eq!(
+        requests_to(&server, "/consumers/oauth2/v2.0/token").await,
+        3
+    );
+
+    let now = Utc::now();
+    let events = source
+        .get_events(now, now.checked_add_days(Days::new(7)).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(events.len(), 3);
+    assert_eq!(events[0].color, Some([0xE7, 0x48, 0x56]));
+    assert_eq!(events[1].color, None);
+    // One refresh, no second sign-in
+    assert_eq!(
+        requests_to(&server, "/consumers/oauth2/v2.0/devicecode").await,
+        1
+    );
+    assert_eq!(
+        requests_to(&server, "/consumers/oauth2/v2.0/token").await,
+        4
+    );
+
+    // The rotated refresh token is saved, encrypted
+    let saved = std::fs::read(&cache).unwrap();
+    assert!(super::crypto::is_encrypted(&saved));
+    assert!(!String::from_utf8_lossy(&saved).contains("refresh2"));
+    std::fs::remove_file(&cache).unwrap();
+}
+
+#[tokio::test]
+async fn test_expired_code_restarts() {
+    let server = MockServer::start().await;
+    mock_device_code(&server, &["code1", "code2", "code3"]).await;
+    mock_poll(&server, "code1", 1, token_error("expired_token")).await;
+    mock_poll(&server, "code2", 0, token_error("authorization_declined")).await;
+    mock_poll(&server, "code3", 1, token("access1", "refresh1", 3600)).await;
+    mock_graph(&server, "access1").await;
+    let (source, cache) = source(&server, "restart");
+
+    assert_eq!(subjects(&source).await.unwrap().len(), 3);
+    assert_eq!(
+        requests_to(&server, "/consumers/oauth2/v2.0/devicecode").await,
+        3
+    );
+    std::fs::remove_file(&cache).unwrap();
+}
+
+#[tokio::test]
+async fn test_slow_down() {
+    let server = MockServer::start().await;
+    mock_device_code(&server, &["code1"]).await;
+    Mock::given(method("POST"))
+        .and(path("/consumers/oauth2/v2.0/token"))
+        .respond_with(token_error("slow_down"))
+        .up_to_n_times(1)
+        .with_priority(1)
+        .mount(&server)
+        .await;
+    mock_poll(&server, "code1", 0, token("access1", "refresh1", 3600)).await;
+    mock_graph(&server, "access1").await;
+    let (source, cache) = source(&server, "slow-down");
+
+    let started = std::time::Instant::now();
+    assert_eq!(subjects(&source).await.unwrap().len(), 3);
+    // The interval grows from 0 to 5 seconds after `slow_down`
+    assert!(started.elapsed() >= std::time::Duration::from_secs(5));
+    std::fs::remove_file(&cache).unwrap();
+}
+
+#[tokio::test]
+async fn test_bad_device_code_fails() {
+    let server = MockServer::start().await;
+    mock_device_code(&server, &["code1"]).await;
+    mock_poll(&server, "code1", 0, token_error("bad_verification_code")).await;
+    let (source, _) = source(&server, "bad-code");
+
+    let err = subjects(&source).await.unwrap_err();
+    assert!(err.to_string().contains("bad_verification_code"));
+    // Not retried with a new code
+    assert_eq!(
+        requests_to(&server, "/consumers/oauth2/v2.0/devicecode").await,
+        1
+    );
+}
+
+#[tokio::test]
+async fn test_revoked_refresh_token_signs_in() {
+    let server = MockServer::start().await;
+    mock_device_code(&server, &["code1", "code2"]).await;
+    mock_poll(&server, "code1", 0, token("access1", "refresh1", 10)).await;
+    mock_refresh(&server, "refresh1", token_error("invalid_grant")).await;
+    mock_poll(&server, "code2", 0, token("access2", "refresh2", 3600)).await;
+    mock_graph(&server, "access1").await;
+    mock_graph(&server, "access2").await;
+    let (source, cache) = source(&server, "revoked");
+
+    assert_eq!(subjects(&source).await.unwrap().len(), 3);
+    assert_eq!(subjects(&source).await.unwrap().len(), 3);
+    assert_eq!(
+        requests_to(&server, "/consumers/oauth2/v2.0/devicecode").await,
+        2
+    );
+    std::fs::remove_file(&cache).unwrap();
+}
+
+#[tokio::test]
+async fn test_graph_errors() {
+    let server = MockServer::start().await;
+    mock_device_code(&server, &["code1"]).await;
+    mock_poll(&server, "code1", 0, token("access1", "refresh1", 3600)).await;
+    Mock::given(method("GET"))
+        .and(path("/v1.0/me/calendarview"))
+        .respond_with(ResponseTemplate::new(503))
+        .mount(&server)
+        .await;
+    let (source, cache) = source(&server, "graph-errors");
+
+    assert!(subjects(&source).await.is_err());
+
+    // Without `MailboxSettings.Read` the events are still shown, only without colors
+    server.reset().await;
+    mock_graph(&server, "access1").await;
+    Mock::given(method("GET"))
+        .and(path("/v1.0/me/outlook/masterCategories"))
+        .respond_with(ResponseTemplate::new(403))
+        .with_priority(1)
+        .mount(&server)
+        .await;
+    let now = Utc::now();
+    let events = source
+        .get_events(now, now.checked_add_days(Days::new(7)).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(events.len(), 3);
+    assert!(events.iter().all(|event| event.color.is_none()));
+    std::fs::remove_file(&cache).unwrap();
+}
@@ -1,12 +1,24 @@
-use chrono::{Days, Local, Timelike};
-use log::{debug, info};
-use serde::{Deserialize, Serialize};
-use token::get_token;
+use std::collections::HashMap;
 
-use crate::config::get_client;
+use chrono::{DateTime, Datelike, Days, Local, NaiveDate, Timelike, Utc};
+use log::{info, warn};
 
+use crate::config::{parse_color, CalendarSourceConfig, Rgb, TodoConfig, WindowConfig};
+use caldav::CalDavSource;
+use graph::GraphSource;
+use ics::IcsSource;
+
+mod caldav;
+mod crypto;
+mod graph;
+mod ics;
+#[cfg(test)]
+mod mock_tests;
+mod timezone;
 mod token;
 
+pub use token::sign_in_prompts;
+
 #[derive(Debug, Clone)]
 pub struct Time {
     pub hour: i32,
@@ -14,13 +26,35 @@ pub struct Time {
     pub second: i32,
 }
 
+#[derive(Debug, Clone)]
+pub struct Date {
+    pub year: i32,
+    pub month: i32,
+    pub day: i32,
+}
+
 #[derive(Debug, Clone)]
 pub struct TodoItemData {
     pub text: String,
+    pub start_date: Date,
     pub start_time: Time,
+    pub end_date: Date,
     pub end_time: Time,
     pub active: bool,
     pub show_time: bool,
+    /// The day of a multi-day event this item is on, starting from 1
+    pub day_index: i32,
+    /// Number of days the event covers, 1 for most events
+    pub day_count: i32,
+    pub location: String,
+    pub organizer: String,
+    pub online_meeting: bool,
+    pub show_as: ShowAs,
+    pub response: Response,
+    /// Color of the first category that has one
+    pub color: Option<Rgb>,
+    /// Name of the calendar source, empty if there is only one source
+    pub calendar_name: String,
 }
 
 #[derive(Debug, Clone)]
@@ -30,120 +64,628 @@ pub struct TodoItemGroupData {
     pub active: bool,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
-struct CalendarItem {
-    subject: String,
-    start: TimeWithZone,
-    end: TimeWithZone,
-    is_all_day: bool,
-    is_cancelled: bool,
-}
-
-impl From<CalendarItem> for TodoItemData {
-    fn from(val: CalendarItem) -> Self {
-        // Assume UTC, and it should be
-        let start_time = val.start.date_time.and_utc().with_timezone(&Local);
-        let end_time = val.end.date_time.and_utc().with_timezone(&Local);
-        let start_time = Time {
-            hour: start_time.hour() as i32,
-            minute: start_time.minute() as i32,
-            second: start_time.second() as i32,
-        };
-        let end_time = Time {
-            hour: end_time.hour() as i32,
-            minute: end_time.minute() as i32,
-            second: end_time.second() as i32,
+/// The free/busy status of an event
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ShowAs {
+    Free,
+    Tentative,
+    #[default]
+    Busy,
+    OutOfOffice,
+    WorkingElsewhere,
+}
+
+/// The response of the user to a meeting invitation
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Response {
+    /// Not a meeting, or the user is the organizer
+    #[default]
+    None,
+    NotResponded,
+    Accepted,
+    Tentative,
+    Declined,
+}
+
+/// A single event returned by a calendar source, in local time
+#[derive(Debug, Clone, Default)]
+pub struct CalendarEvent {
+    pub subject: String,
+    pub start: DateTime<Local>,
+    pub end: DateTime<Local>,
+    pub is_all_day: bool,
+    pub is_cancelled: bool,
+    pub location: String,
+    pub organizer: String,
+    pub online_meeting_url: Option<String>,
+    pub show_as: ShowAs,
+    pub response: Response,
+    pub categories: Vec<String>,
+    pub color: Option<Rgb>,
+    pub calendar_name: String,
+}
+
+impl From<DateTime<Local>> for Date {
+    fn from(time: DateTime<Local>) -> Self {
+        Date {
+            year: time.year(),
+            month: time.month() as i32,
+            day: time.day() as i32,
+        }
+    }
+}
+
+impl From<DateTime<Local>> for Time {
+    fn from(time: DateTime<Local>) -> Self {
+        Time {
+            hour: time.hour() as i32,
+            minute: time.minute() as i32,
+            second: time.second() as i32,
+        }
+    }
+}
+
+impl CalendarEvent {
+    /// The dates the event covers, an event ending at midnight does not cover the next day
+    fn dates(&self) -> impl Iterator<Item = NaiveDate> {
+        let first = self.start.date_naive();
+        let last = if self.end > self.start {
+            (self.end - chrono::Duration::nanoseconds(1)).date_naive()
+        } else {
+            first
         };
+        first.iter_days().take_while(move |date| *date <= last)
+    }
+
+    /// The part of the event on the date
+    fn item_on(&self, date: NaiveDate) -> TodoItemData {
+        let day_start = local_midnight(date).with_timezone(&Local);
+        let day_end = local_midnight(date.succ_opt().unwrap()).with_timezone(&Local);
+        let start = self.start.max(day_start);
+        let end = self.end.min(day_end).max(start);
+        // A day fully covered by a multi-day event is shown like an all-day event
+        let whole_day = self.is_all_day || (start == day_start && end == day_end);
         TodoItemData {
-            text: val.subject,
-            start_time,
-            end_time,
-            active: !val.is_cancelled,
-            show_time: !val.is_all_day,
+            text: self.subject.clone(),
+            start_date: start.into(),
+            start_time: start.into(),
+            end_date: end.into(),
+            end_time: end.into(),
+            active: !self.is_cancelled,
+            show_time: !whole_day,
+            day_index: (date - self.start.date_naive()).num_days() as i32 + 1,
+            day_count: self.dates().count() as i32,
+            location: self.location.clone(),
+            organizer: self.organizer.clone(),
+            online_meeting: self.online_meeting_url.is_some(),
+            show_as: self.show_as,
+            response: self.response,
+            color: self.color,
+            calendar_name: self.calendar_name.clone(),
+        }
+    }
+}
+
+/// A calendar the todo list can be built from
+pub trait CalendarSource {
+    /// The display name of the source
+    fn name(&self) -> &str;
+    /// Get the events in the range `[start, end)`
+    async fn get_events(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> anyhow::Result<Vec<CalendarEvent>>;
+}
+
+/// All supported calendar sources
+#[derive(Debug, Clone)]
+pub enum Source {
+    Outlook(GraphSource),
+    Ics(IcsSource),
+    CalDav(CalDavSource),
+}
+
+impl CalendarSource for Source {
+    fn name(&self) -> &str {
+        match self {
+            Source::Outlook(source) => source.name(),
+            Source::Ics(source) => source.name(),
+            Source::CalDav(source) => source.name(),
+        }
+    }
+
+    async fn get_events(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> anyhow::Result<Vec<CalendarEvent>> {
+        match self {
+            Source::Outlook(source) => source.get_events(start, end).await,
+            Source::Ics(source) => source.get_events(start, end).await,
+            Source::CalDav(source) => source.get_events(start, end).await,
+        }
+    }
+}
+
+/// Create the calendar sources listed in the config, fall back to the default Outlook calendar
+pub fn get_sources(cfg: &TodoConfig) -> Vec<Source> {
+    cfg.sources()
+        .iter()
+        .map(|source| match source {
+            CalendarSourceConfig::Outlook(outlook) => {
+                Source::Outlook(GraphSource::new(outlook, cfg))
+            }
+            CalendarSourceConfig::Ics(ics) => Source::Ics(IcsSource::new(ics)),
+            CalendarSourceConfig::CalDav(caldav) => Source::CalDav(CalDavSource::new(caldav)),
+        })
+        .collect()
+}
+
+/// Keep the tokens of the Outlook sources fresh in the background
+pub async fn refresh_tokens(sources: &[Source]) {
+    futures::future::join_all(sources.iter().filter_map(|source| match source {
+        Source::Outlook(graph) => Some(graph.keep_token_fresh()),
+        _ => None,
+    }))
+    .await;
+}
+
+/// Print the calendars of the Outlook accounts, for choosing the `calendars` in the config
+pub async fn list_calendars(cfg: &TodoConfig, account: Option<&str>) -> anyhow::Result<()> {
+    for graph in outlook_sources(cfg, account)? {
+        println!("{}:", graph.name());
+        for (owner, calendar) in graph.list_calendars().await? {
+            let default = if calendar.is_default_calendar {
+                " (default)"
+            } else {
+                ""
+            };
+            println!(
+                "  {owner}  {}{default}\n    id: {}",
+                calendar.name, calendar.id
+            );
+        }
+    }
+    Ok(())
+}
+
+/// The Outlook sources the `auth` and `calendars` commands work on, all of them or the one named `account`
+fn outlook_sources(cfg: &TodoConfig, account: Option<&str>) -> anyhow::Result<Vec<GraphSource>> {
+    let sources: Vec<GraphSource> = get_sources(cfg)
+        .into_iter()
+        .filter_map(|source| match source {
+            Source::Outlook(graph) => Some(graph),
+            _ => None,
+        })
+        .filter(|graph| account.is_none_or(|account| graph.name() == account))
+        .collect();
+    if sources.is_empty() {
+        match account {
+            Some(account) => anyhow::bail!("No Outlook account named \"{account}\""),
+            None => anyhow::bail!("No Outlook account configured"),
+        }
+    }
+    Ok(sources)
+}
+
+/// Sign in to the Outlook accounts with the device code, replacing the saved tokens
+pub async fn login(cfg: &TodoConfig, account: Option<&str>) -> anyhow::Result<()> {
+    // The window shows the device codes on the sign-in panel, print them on the terminal here
+    let mut prompts = sign_in_prompts();
+    let printer = tokio::spawn(async move {
+        while prompts.changed().await.is_ok() {
+            for prompt in prompts.borrow_and_update().iter() {
+                println!(
+                    "{}: open {} in a browser and enter the code {} to sign in",
+                    prompt.account, prompt.verification_uri, prompt.user_code
+                );
+            }
         }
+    });
+    let result = async {
+        for graph in outlook_sources(cfg, account)? {
+            graph.account().login().await?;
+            println!("{}: signed in", graph.name());
+        }
+        anyhow::Ok(())
     }
+    .await;
+    printer.abort();
+    result
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
-struct CalendarItems {
-    value: Vec<CalendarItem>,
+/// Print the saved sign-in of the Outlook accounts without signing in
+pub async fn auth_status(cfg: &TodoConfig, account: Option<&str>) -> anyhow::Result<()> {
+    for graph in outlook_sources(cfg, account)? {
+        let status = match graph.account().status().await {
+            Ok(Some(status)) => status,
+            Ok(None) => {
+                println!("{}: not signed in", graph.name());
+                continue;
+            }
+            Err(e) => {
+                println!("{}: failed to read token cache: {e}", graph.name());
+                continue;
+            }
+        };
+        let expiry = if status.expires_on > Utc::now() {
+            "valid"
+        } else if status.refreshable {
+            "expired, will be refreshed"
+        } else {
+            "expired"
+        };
+        println!("{}:", status.account);
+        if let Some(user) = &status.user {
+            println!("  user:    {user}");
+        }
+        println!("  tenant:  {}", status.tenant);
+        if let Some(tenant_id) = &status.tenant_id {
+            println!("  tenant id: {tenant_id}");
+        }
+        println!("  app id:  {}", status.app_id);
+        println!("  scopes:  {}", status.scopes.join(" "));
+        println!(
+            "  expires: {} ({expiry})",
+            status.expires_on.with_timezone(&Local)
+        );
+        println!("  cache:   {}", status.cache_path.display());
+    }
+    Ok(())
 }
 
-impl From<CalendarItems> for Vec<TodoItemGroupData> {
-    fn from(val: CalendarItems) -> Self {
-        let mut groups = std::collections::BTreeMap::new();
-        for item in val.value {
-            let group_date = item
-                .start
-                .date_time
-                .and_utc()
-                .with_timezone(&Local)
-                .date_naive();
-            let group_name = group_date.format("%m月%d日").to_string();
+/// Delete the token caches of the Outlook accounts
+pub async fn logout(cfg: &TodoConfig, account: Option<&str>) -> anyhow::Result<()> {
+    for graph in outlook_sources(cfg, account)? {
+        if graph.account().logout().await? {
+            println!("{}: signed out", graph.name());
+        } else {
+            println!("{}: not signed in", graph.name());
+        }
+    }
+    Ok(())
+}
+
+/// Merge events into day groups in `[first, last)`, a multi-day event appears in every day
+/// it covers. All-day items come first in a group, the others are ordered by start time
+fn group_events(
+    events: Vec<CalendarEvent>,
+    first: NaiveDate,
+    last: NaiveDate,
+    group_name: impl Fn(NaiveDate) -> String,
+) -> Vec<TodoItemGroupData> {
+    let mut events = events;
+    events.sort_by_key(|event| event.start);
+    let mut groups = std::collections::BTreeMap::new();
+    for event in events {
+        for group_date in event.dates() {
+            if group_date < first || group_date >= last {
+                continue;
+            }
             let group = groups
                 .entry(group_date)
                 .or_insert_with(|| TodoItemGroupData {
-                    group_name,
+                    group_name: group_name(group_date),
                     items: vec![],
                     active: true,
                 });
-            group.items.push(item.into());
-        }
-        groups.into_values().collect()
-    }
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
-struct TimeWithZone {
-    date_time: chrono::NaiveDateTime,
-    time_zone: String,
-}
-
-pub async fn get_todo_list(app_id: String) -> anyhow::Result<Vec<TodoItemGroupData>> {
-    info!("Getting todo list");
-    let token = get_token(app_id).await?;
-    let client = get_client();
-    let start_of_the_day = Local::now()
-        .date_naive()
-        .and_hms_opt(0, 0, 0)
-        .unwrap()
-        .and_utc()
-        .format("%Y-%m-%dT%H:%M:%S%.fZ");
-    let end_of_the_day = Local::now()
-        .checked_add_days(Days::new(7))
-        .unwrap()
-        .date_naive()
-        .and_hms_opt(0, 0, 0)
-        .unwrap()
-        .and_utc()
-        .format("%Y-%m-%dT%H:%M:%S%.fZ");
-    let url = format!(
-        "https://graph.microsoft.com/v1.0/me/calendarview?startDateTime={start_of_the_day}&endDateTime={end_of_the_day}",
-    );
-    debug!("Requesting todo list from {url}");
-    // println!("curl -H 'Authorization: Bearer {}' '{}' ", token, url);
-    let resp = client.get(&url).bearer_auth(token).send().await?;
-    let body = resp.text().await?;
-    let items: CalendarItems = serde_json::from_str(&body)?;
+            group.items.push(event.item_on(group_date));
+        }
+    }
+    for group in groups.values_mut() {
+        // Stable sort, items with the same key stay in the order of start time
+        group.items.sort_by_key(|item| item.show_time);
+    }
+    groups.into_values().collect()
+}
+
+/// The UTC time of the local midnight at the beginning of the date
+fn local_midnight(date: NaiveDate) -> DateTime<Utc> {
+    let midnight = date.and_hms_opt(0, 0, 0).unwrap();
+    // Some time zones switch DST at midnight, the day starts at 01:00 then
+    midnight
+        .and_local_timezone(Local)
+        .earliest()
+        .or_else(|| {
+            (midnight + chrono::Duration::hours(1))
+                .and_local_timezone(Local)
+                .earliest()
+        })
+        .map(|time| time.to_utc())
+        .unwrap_or_else(|| midnight.and_utc())
+}
+
+/// The group name of the date, see `TodoConfig::group_name_format`
+fn format_group_name(
+    date: NaiveDate,
+    today: NaiveDate,
+    cfg: &TodoConfig,
+    window: &WindowConfig,
+) -> String {
+    let relative_name = usize::try_from((date - today).num_days())
+        .ok()
+        .and_then(|offset| cfg.relative_day_names.get(offset))
+        .map_or(String::new(), |name| name.replace('%', "%%"));
+    // Replace `%R` before chrono sees it, chrono has its own `%R`
+    let format = cfg.group_name_format.replace("%R", &relative_name);
+    window.format_naive_date(&date, &format).trim().to_string()
+}
+
+/// Color the events the source left uncolored with the configured category colors
+fn apply_category_colors(events: &mut [CalendarEvent], colors: &HashMap<String, String>) {
+    for event in events.iter_mut().filter(|event| event.color.is_none()) {
+        event.color = event
+            .categories
+            .iter()
+            .filter_map(|category| colors.get(category))
+            .find_map(|color| {
+                let rgb = parse_color(color);
+                if rgb.is_none() {
+                    warn!("Invalid category color {color}, expected #RRGGBB");
+                }
+                rgb
+            });
+    }
+}
+
+/// Get the events of a source in the shown days, named after the source when there are more
+async fn get_source_events(
+    source: &Source,
+    today: NaiveDate,
+    days: u64,
+    named: bool,
+) -> anyhow::Result<Vec<CalendarEvent>> {
+    let last_day = today.checked_add_days(Days::new(days)).unwrap();
+    let mut events = source
+        .get_events(local_midnight(today), local_midnight(last_day))
+        .await?;
+    // Tell the accounts and calendars apart when there are more than one
+    if named {
+        for event in events.iter_mut() {
+            event.calendar_name = source.name().to_string();
+        }
+    }
+    Ok(events)
+}
+
+/// Group the events of all sources into the todo list shown from today
+fn build_todo_list(
+    mut events: Vec<CalendarEvent>,
+    cfg: &TodoConfig,
+    window: &WindowConfig,
+) -> Vec<TodoItemGroupData> {
+    let now = Local::now();
+    let today = now.date_naive();
+    let days = cfg.lookahead_days.max(1);
+    let last_day = today.checked_add_days(Days::new(days)).unwrap();
+    apply_category_colors(&mut events, &cfg.category_colors);
+    if !cfg.include_past_events {
+        events.retain(|event| event.end > now || event.start >= now);
+    }
     info!(
-        "Todo list retrieved, {} items in next 7 days",
-        items.value.len()
+        "Todo list updated, {} items in next {days} days",
+        events.len()
     );
-    Ok(items.into())
+    group_events(events, today, last_day, |date| {
+        format_group_name(date, today, cfg, window)
+    })
+}
+
+/// Fetch every source on its own every `interval` and `publish` the merged todo list whenever
+/// one of them is done, so a source waiting for a sign-in doesn't hold back the others. A
+/// source that fails keeps showing the events it got last time
+pub async fn update_todo_list(
+    sources: &[Source],
+    cfg: &TodoConfig,
+    window: &WindowConfig,
+    interval: std::time::Duration,
+    publish: impl Fn(Vec<TodoItemGroupData>) + Sync,
+) {
+    let days = cfg.lookahead_days.max(1);
+    let latest = std::sync::Mutex::new(vec![Vec::<CalendarEvent>::new(); sources.len()]);
+    futures::future::join_all(sources.iter().enumerate().map(|(index, source)| {
+        let latest = &latest;
+        let publish = &publish;
+        async move {
+            loop {
+                info!("Getting events from {}", source.name());
+                let today = Local::now().date_naive();
+                match get_source_events(source, today, days, sources.len() > 1).await {
+                    Ok(events) => {
+                        let events = {
+                            let mut latest = latest.lock().unwrap();
+                            latest[index] = events;
+                            latest.concat()
+                        };
+                        publish(build_todo_list(events, cfg, window));
+                    }
+                    Err(e) => warn!("Failed to get events from {}, error: {e}", source.name()),
+                }
+                tokio::time::sleep(interval).await;
+            }
+        }
+    }))
+    .await;
 }
 
 #[cfg(test)]
 mod tests {
+    use chrono::TimeZone;
+
     use super::*;
 
+    fn event(subject: &str, day: u32, hour: u32) -> CalendarEvent {
+        let start = Local.with_ymd_and_hms(2024, 6, day, hour, 0, 0).unwrap();
+        CalendarEvent {
+            subject: subject.to_string(),
+            start,
+            end: start + chrono::Duration::hours(1),
+            ..Default::default()
+        }
+    }
+
+    fn date(day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, 6, day).unwrap()
+    }
+
+    fn short_name(date: NaiveDate) -> String {
+        date.format("%m月%d日").to_string()
+    }
+
+    #[test]
+    fn test_group_events() {
+        // Events from different sources arrive unordered
+        let groups = group_events(
+            vec![event("b", 2, 10), event("c", 1, 15), event("a", 1, 9)],
+            date(1),
+            date(8),
+            short_name,
+        );
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].group_name, "06月01日");
+        let texts: Vec<_> = groups[0].items.iter().map(|i| i.text.as_str()).collect();
+        assert_eq!(texts, ["a", "c"]);
+        assert_eq!(groups[1].group_name, "06月02日");
+        assert_eq!(groups[1].items[0].text, "b");
+    }
+
+    #[test]
+    fn test_multi_day_event() {
+        let mut offsite = event("offsite", 1, 15);
+        offsite.end = Local.with_ymd_and_hms(2024, 6, 3, 11, 0, 0).unwrap();
+        let groups = group_events(
+            vec![offsite, event("a", 2, 9)],
+            date(1),
+            date(8),
+            short_name,
+        );
+        assert_eq!(groups.len(), 3);
+        let days: Vec<_> = groups
+            .iter()
+            .map(|group| (group.items[0].day_index, group.items[0].day_count))
+            .collect();
+        assert_eq!(days, [(1, 3), (2, 3), (3, 3)]);
+        // Starts in the afternoon of the first day
+        assert!(groups[0].items[0].show_time);
+        assert_eq!(groups[0].items[0].start_time.hour, 15);
+        // The whole second day is covered, so it goes before the other events
+        assert_eq!(groups[1].items[0].text, "offsite");
+        assert!(!groups[1].items[0].show_time);
+        assert_eq!(groups[1].items[1].text, "a");
+        // Ends in the morning of the last day
+        assert!(groups[2].items[0].show_time);
+        assert_eq!(groups[2].items[0].start_time.hour, 0);
+        assert_eq!(groups[2].items[0].end_time.hour, 11);
+    }
+
+    #[test]
+    fn test_all_day_events() {
+        let mut holiday = event("holiday", 1, 0);
+        holiday.end = Local.with_ymd_and_hms(2024, 6, 8, 0, 0, 0).unwrap();
+        holiday.is_all_day = true;
+        // Only the days in the window are shown
+        let groups = group_events(
+            vec![event("a", 3, 9), holiday],
+            date(3),
+            date(5),
+            short_name,
+        );
+        assert_eq!(groups.len(), 2);
+        let texts: Vec<_> = groups[0].items.iter().map(|i| i.text.as_str()).collect();
+        assert_eq!(texts, ["holiday", "a"]);
+        assert_eq!(groups[0].items[0].day_index, 3);
+        assert_eq!(groups[1].items[0].day_count, 7);
+    }
+
+    #[test]
+    fn test_category_colors() {
+        assert_eq!(parse_color("#0078D4"), Some([0x00, 0x78, 0xd4]));
+        assert_eq!(parse_color("0078D4"), None);
+        assert_eq!(parse_color("#07D"), None);
+        let colors = HashMap::from([
+            ("Customer".to_string(), "#FF0000".to_string()),
+            ("Internal".to_string(), "blue".to_string()),
+        ]);
+        let mut events = vec![event("a", 3, 9), event("b", 3, 10), event("c", 3, 11)];
+        events[0].categories = vec!["Internal".to_string(), "Customer".to_string()];
+        // Colors from the source are kept
+        events[1].categories = vec!["Customer".to_string()];
+        events[1].color = Some([0, 0, 255]);
+        apply_category_colors(&mut events, &colors);
+        assert_eq!(events[0].color, Some([255, 0, 0]));
+        assert_eq!(events[1].color, Some([0, 0, 255]));
+        assert_eq!(events[2].color, None);
+    }
+
+    #[test]
+    fn test_format_group_name() {
+        let cfg = TodoConfig {
+            group_name_format: "%R %m月%d日 %A".to_string(),
+            relative_day_names: vec!["今天".to_string(), "明天".to_string(), "50%".to_string()],
+            ..Default::default()
+        };
+        let window = WindowConfig::default();
+        // 2024-06-03 is a Monday
+        let name = |day| format_group_name(date(day), date(3), &cfg, &window);
+        assert_eq!(name(3), "今天 06月03日 星期一");
+        assert_eq!(name(4), "明天 06月04日 星期二");
+        // A `%` in the name is not a format spec
+        assert_eq!(name(5), "50% 06月05日 星期三");
+        assert_eq!(name(9), "06月09日 星期日");
+    }
+
     #[tokio::test]
-    #[ignore = "Needs interactive login"]
-    async fn test_get_todo_list() {
-        let app_id = std::env::var("AAD_APP_ID").unwrap().to_string();
-        let todo_list = get_todo_list(app_id).await.unwrap();
-        println!("{todo_list:?}");
+    async fn test_slow_source_does_not_block() {
+        use crate::config::{CalDavSourceConfig, IcsSourceConfig};
+        use wiremock::{matchers::method, Mock, MockServer, ResponseTemplate};
+
+        // A source that never answers, like an account waiting for a sign-in
+        let server = MockServer::start().await;
+        Mock::given(method("REPORT"))
+            .respond_with(ResponseTemplate::new(207).set_delay(std::time::Duration::from_secs(60)))
+            .mount(&server)
+            .await;
+        let path =
+            std::env::temp_dir().join(format!("todo-station-slow-{}.ics", std::process::id()));
+        let today = Local::now().date_naive().format("%Y%m%d");
+        std::fs::write(
+            &path,
+            format!(
+                "BEGIN:VCALENDAR\r\nBEGIN:VEVENT\r\nUID:a\r\nSUMMARY:Holiday\r\n\
+                 DTSTART;VALUE=DATE:{today}\r\nEND:VEVENT\r\nEND:VCALENDAR\r\n"
+            ),
+        )
+        .unwrap();
+        let sources = vec![
+            Source::CalDav(CalDavSource::new(&CalDavSourceConfig {
+                name: "CalDAV".to_string(),
+                url: server.uri(),
+                username: None,
+                password: None,
+                token: None,
+            })),
+            Source::Ics(IcsSource::new(&IcsSourceConfig {
+                name: "ICS".to_string(),
+                paths: vec![path.clone()],
+            })),
+        ];
+        let published = std::sync::Mutex::new(vec![]);
+        let _ = tokio::time::timeout(
+            std::time::Duration::from_secs(1),
+            update_todo_list(
+                &sources,
+                &TodoConfig::default(),
+                &WindowConfig::default(),
+                std::time::Duration::from_secs(600),
+                |groups| published.lock().unwrap().push(groups),
+            ),
+        )
+        .await;
+        std::fs::remove_file(&path).unwrap();
+        let published = published.into_inner().unwrap();
+        assert_eq!(published.len(), 1);
+        assert_eq!(published[0][0].items[0].text, "Holiday");
+        assert_eq!(published[0][0].items[0].calendar_name, "ICS");
     }
 }
@@ -1,231 +1,1016 @@
-use std::{fs::read, sync::Arc};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::{fmt, fs::read, sync::Arc, time::Duration};
 
-use crate::device_code_flow;
+use crate::device_code_flow::{
+    self, DeviceCodeAuthorization, DeviceCodeError, DeviceCodePhaseOneResponse,
+};
 use futures::StreamExt;
-use log::{debug, warn};
+use log::{debug, info, warn};
 use platform_dirs::AppDirs;
 use serde::{Deserialize, Serialize};
 
-use crate::config::get_client;
+use super::crypto::{is_encrypted, write_private, KeySource, TokenCipher};
+use crate::config::{get_client, TodoConfig};
+
+// Refresh this long before the access token expires
+const REFRESH_MARGIN: chrono::Duration = chrono::Duration::minutes(5);
+// Wait before retrying a failed background refresh, doubled after each failure
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+const MAX_RETRY_INTERVAL: Duration = Duration::from_secs(30 * 60);
+const SCOPES: &[&str] = &["openid", "offline_access", "user.read", "Calendars.Read"];
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct TokenCache {
     access_token: String,
     expires_on: chrono::DateTime<chrono::Utc>,
     refresh_token: String,
+    /// Caches written before the tenant was configurable are all from `consumers`
+    #[serde(default = "legacy_tenant")]
+    tenant: String,
+    #[serde(default)]
+    app_id: String,
+    /// The scopes granted to the access token, space separated
+    #[serde(default)]
+    scope: String,
+    /// Only read by `auth status`, it's never sent anywhere
+    #[serde(default)]
+    id_token: Option<String>,
+}
+
+fn legacy_tenant() -> String {
+    "consumers".to_string()
+}
+
+impl TokenCache {
+    fn matches(&self, app_id: &str, tenant: &str) -> bool {
+        // Old caches don't record the app id
+        self.tenant == tenant && (self.app_id.is_empty() || self.app_id == app_id)
+    }
 }
 
 lazy_static::lazy_static! {
-    static ref TOKEN_CACHE: tokio::sync::Mutex<TokenCache> = tokio::sync::Mutex::new(TokenCache {
-        access_token: "".to_string(),
-        expires_on: chrono::DateTime::UNIX_EPOCH,
-        refresh_token: "".to_string(),
-    });
+    static ref TOKEN_STORES: std::sync::Mutex<HashMap<PathBuf, Arc<TokenStore>>> =
+        Default::default();
+    static ref SIGN_IN_PROMPTS: tokio::sync::watch::Sender<Vec<SignInPrompt>> =
+        tokio::sync::watch::Sender::new(vec![]);
+}
+
+/// What the user needs to sign in to an account with the device code
+#[derive(Debug, Clone)]
+pub struct SignInPrompt {
+    pub account: String,
+    pub user_code: String,
+    pub verification_uri: String,
+    pub expires_on: chrono::DateTime<chrono::Utc>,
+}
+
+/// The pending device code sign-ins, in the order they started
+pub fn sign_in_prompts() -> tokio::sync::watch::Receiver<Vec<SignInPrompt>> {
+    SIGN_IN_PROMPTS.subscribe()
+}
+
+fn state_dir() -> PathBuf {
+    AppDirs::new(Some("todo-station"), false).unwrap().state_dir
+}
+
+/// Path of the token cache of an account, relative paths are in the state directory.
+/// The default account keeps the file name used before there were multiple accounts
+pub fn cache_path(account: &str, token_cache: Option<&Path>) -> PathBuf {
+    let state_dir = state_dir();
+    match token_cache {
+        Some(path) => state_dir.join(path),
+        None if account == "Outlook" => state_dir.join("token_cache.json"),
+        None => {
+            let name: String = account
+                .chars()
+                .map(|c| if c.is_alphanumeric() { c } else { '_' })
+                .collect();
+            state_dir.join(format!("token_cache_{name}.json"))
+        }
+    }
+}
+
+/// Where the key encrypting the token caches comes from
+pub fn key_source(cfg: &TodoConfig) -> KeySource {
+    match (&cfg.token_passphrase, &cfg.token_key_file) {
+        (Some(passphrase), _) => KeySource::Passphrase(passphrase.clone()),
+        (None, Some(path)) => KeySource::File(path.clone()),
+        (None, None) => KeySource::MachineId {
+            legacy_key: state_dir().join("token_cache.key"),
+        },
+    }
+}
+
+/// The tokens of one account, shared by everything using the same cache file
+#[derive(Debug)]
+struct TokenStore {
+    path: PathBuf,
+    cipher: TokenCipher,
+    cache: tokio::sync::Mutex<TokenCache>,
+    // Only one refresh at a time, the others use its result
+    refresh_lock: tokio::sync::Mutex<()>,
+    // Only one device code sign-in at a time, held while waiting for the user
+    sign_in_lock: tokio::sync::Mutex<()>,
+}
+
+fn token_store(path: &Path, key_source: &KeySource) -> Arc<TokenStore> {
+    TOKEN_STORES
+        .lock()
+        .unwrap()
+        .entry(path.to_path_buf())
+        .or_insert_with(|| {
+            Arc::new(TokenStore {
+                path: path.to_path_buf(),
+                cipher: TokenCipher::new(key_source),
+                cache: tokio::sync::Mutex::new(TokenCache {
+                    access_token: "".to_string(),
+                    expires_on: chrono::DateTime::UNIX_EPOCH,
+                    refresh_token: "".to_string(),
+                    tenant: "".to_string(),
+                    app_id: "".to_string(),
+                    scope: "".to_string(),
+                    id_token: None,
+                }),
+                refresh_lock: tokio::sync::Mutex::new(()),
+                sign_in_lock: tokio::sync::Mutex::new(()),
+            })
+        })
+        .clone()
 }
 
-trait CacheSingleton {
+/// A Microsoft account signed in with the device code flow
+#[derive(Debug, Clone)]
+pub struct Account {
+    name: String,
+    login_url: String,
+    app_id: String,
+    tenant: String,
+    scopes: Vec<String>,
+    store: Arc<TokenStore>,
+}
+
+impl Account {
+    /// `extra_scopes` are requested along with the ones needed for the own calendars
+    pub fn new(
+        name: &str,
+        login_url: &str,
+        app_id: &str,
+        tenant: &str,
+        cache_path: &Path,
+        key_source: &KeySource,
+        extra_scopes: &[&str],
+    ) -> Self {
+        Self {
+            name: name.to_string(),
+            login_url: login_url.trim_end_matches('/').to_string(),
+            app_id: app_id.to_string(),
+            tenant: tenant.to_string(),
+            scopes: SCOPES
+                .iter()
+                .chain(extra_scopes)
+                .map(|scope| scope.to_string())
+                .collect(),
+            store: token_store(cache_path, key_source),
+        }
+    }
+}
+
+trait CacheStore {
     async fn is_expired(&self) -> bool;
+    async fn expires_within(&self, margin: chrono::Duration) -> bool;
     async fn expire_on(&self) -> chrono::DateTime<chrono::Utc>;
     async fn get_access_token(&self) -> String;
     async fn get_refresh_token(&self) -> String;
-    async fn assign(&self, access_token: String, expires_in: u64, refresh_token: String);
+    async fn matches(&self, app_id: &str, tenant: &str) -> bool;
+    async fn reset(&self, app_id: &str, tenant: &str);
+    async fn assign(&self, token: TokenResponse);
     async fn load(&self) -> anyhow::Result<()>;
     async fn save(&self) -> anyhow::Result<()>;
 }
 
-impl CacheSingleton for tokio::sync::Mutex<TokenCache> {
+impl CacheStore for TokenStore {
     async fn is_expired(&self) -> bool {
         debug!("Checking if token cache is expired");
-        let cache = self.lock().await;
+        let cache = self.cache.lock().await;
         debug!("Token cache expires on {}", cache.expires_on);
-        debug!(
-            "Token cache expired: {}",
-            cache.expires_on <= chrono::Utc::now() - chrono::Duration::seconds(30)
-        );
-        cache.expires_on <= chrono::Utc::now() - chrono::Duration::seconds(30)
+        // Leave some time for the request using the token
+        let expired = cache.expires_on <= chrono::Utc::now() + chrono::Duration::seconds(30);
+        debug!("Token cache expired: {expired}");
+        expired
+    }
+
+    async fn expires_within(&self, margin: chrono::Duration) -> bool {
+        let cache = self.cache.lock().await;
+        cache.expires_on <= chrono::Utc::now() + margin
     }
 
     async fn expire_on(&self) -> chrono::DateTime<chrono::Utc> {
-        let cache = self.lock().await;
+        let cache = self.cache.lock().await;
         cache.expires_on
     }
 
     async fn get_access_token(&self) -> String {
-        let cache = self.lock().await;
+        let cache = self.cache.lock().await;
         cache.access_token.clone()
     }
 
     async fn get_refresh_token(&self) -> String {
-        let cache = self.lock().await;
+        let cache = self.cache.lock().await;
         cache.refresh_token.clone()
     }
 
-    async fn assign(&self, access_token: String, expires_in: u64, refresh_token: String) {
-        let mut cache = self.lock().await;
-        cache.access_token = access_token;
-        cache.expires_on = chrono::Utc::now() + chrono::Duration::seconds(expires_in as i64);
-        cache.refresh_token = refresh_token;
+    async fn matches(&self, app_id: &str, tenant: &str) -> bool {
+        let cache = self.cache.lock().await;
+        cache.matches(app_id, tenant)
+    }
+
+    async fn reset(&self, app_id: &str, tenant: &str) {
+        let mut cache = self.cache.lock().await;
+        cache.access_token = String::new();
+        cache.expires_on = chrono::DateTime::UNIX_EPOCH;
+        cache.refresh_token = String::new();
+        cache.tenant = tenant.to_string();
+        cache.app_id = app_id.to_string();
+        cache.scope = String::new();
+        cache.id_token = None;
+    }
+
+    async fn assign(&self, token: TokenResponse) {
+        let mut cache = self.cache.lock().await;
+        cache.access_token = token.access_token;
+        cache.expires_on = chrono::Utc::now() + chrono::Duration::seconds(token.expires_in as i64);
+        // The token endpoint may leave these out, keep the old ones then
+        if let Some(refresh_token) = token.refresh_token {
+            cache.refresh_token = refresh_token;
+        }
+        if let Some(scope) = token.scope {
+            cache.scope = scope;
+        }
+        if let Some(id_token) = token.id_token {
+            cache.id_token = Some(id_token);
+        }
     }
 
     async fn load(&self) -> anyhow::Result<()> {
-        debug!("Loading token cache");
-        let path = AppDirs::new(Some("todo-station"), false).unwrap().state_dir;
-        debug!(
-            "Reading token cache from {}",
-            path.join("token_cache.json").display()
-        );
-        let cache = read(path.join("token_cache.json"))?;
-        let cache: TokenCache = serde_json::from_slice(&cache)?;
-        let mut token_cache = self.lock().await;
-        *token_cache = cache;
+        debug!("Reading token cache from {}", self.path.display());
+        let data = read(&self.path)?;
+        let encrypted = is_encrypted(&data);
+        let data = if encrypted {
+            self.cipher.decrypt(&data)?
+        } else {
+            data
+        };
+        let cache: TokenCache = serde_json::from_slice(&data)?;
+        *self.cache.lock().await = cache;
         debug!("Token cache loaded");
+        if !encrypted {
+            info!("Encrypting plaintext token cache {}", self.path.display());
+            if let Err(e) = self.save().await {
+                warn!("Failed to encrypt the token cache, keeping it as it is: {e}");
+            }
+        }
         Ok(())
     }
 
     async fn save(&self) -> anyhow::Result<()> {
         debug!("Saving token cache");
-        let path = AppDirs::new(Some("todo-station"), false).unwrap().state_dir;
-        debug!("Creating cache directory at {path:?}");
-        std::fs::create_dir_all(&path)?;
         let cache = {
-            let token_cache = self.lock().await;
+            let token_cache = self.cache.lock().await;
             serde_json::to_vec(&*token_cache)?
         };
-        debug!("Writing token cache to file");
-        std::fs::write(path.join("token_cache.json"), cache)?;
+        debug!("Writing token cache to {}", self.path.display());
+        write_private(&self.path, &self.cipher.encrypt(&cache)?)?;
         debug!("Token cache saved");
         Ok(())
     }
 }
 
-async fn do_get_token(app_id: String) -> anyhow::Result<String> {
+async fn do_get_token(account: &Account) -> anyhow::Result<String> {
+    let store = &account.store;
+    // Waiting for the user can take forever, so the refresh lock is only taken to store the
+    // token, a background refresh is not blocked by the sign-in
+    let _guard = store.sign_in_lock.lock().await;
     let access_token = {
         debug!("Acquiring token with device code flow");
-        if TOKEN_CACHE.is_expired().await {
+        // Another task may have signed in while waiting for the lock
+        if store.is_expired().await {
             debug!("Token cache is expired, acquiring new token with device code flow");
             let client = Arc::new(get_client());
-            let phase1 = device_code_flow::start(
-                client.clone(),
-                "consumers",
-                &app_id,
-                &["openid", "offline_access", "user.read", "Calendars.Read"],
-            )
-            .await?;
-            debug!("Phase 1 done, waiting for user to authorize");
-            println!("{}", phase1.message());
-            let (access_token, expires_in, refresh_token) = loop {
-                match phase1.stream().next().await {
-                    Some(Ok(resp)) => {
-                        break (
-                            resp.access_token().to_owned(),
-                            resp.expires_in,
-                            resp.refresh_token()
-                                .ok_or(anyhow::anyhow!(
-                                    "Failed to extract refresh token from auth response"
-                                ))?
-                                .to_owned(),
-                        );
-                    }
-                    Some(Err(err)) => {
-                        if err.to_string().contains("authorization_pending") {
-                            debug!("Authorization pending");
-                            continue;
+            let scopes: Vec<&str> = account.scopes.iter().map(|scope| scope.as_str()).collect();
+            // Dismiss the prompt however the sign-in ends
+            let result = async {
+                loop {
+                    let phase1 = device_code_flow::start(
+                        client.clone(),
+                        &account.login_url,
+                        &account.tenant,
+                        &account.app_id,
+                        &scopes,
+                    )
+                    .await?;
+                    debug!("Phase 1 done, waiting for user to authorize");
+                    info!("{}: {}", account.name, phase1.message());
+                    SIGN_IN_PROMPTS.send_modify(|prompts| {
+                        prompts.retain(|prompt| prompt.account != account.name);
+                        prompts.push(SignInPrompt {
+                            account: account.name.clone(),
+                            user_code: phase1.user_code().to_string(),
+                            verification_uri: phase1.verification_uri().to_string(),
+                            expires_on: chrono::Utc::now()
+                                + chrono::Duration::seconds(phase1.expires_in() as i64),
+                        })
+                    });
+                    match wait_for_authorization(&phase1).await {
+                        Ok(resp) => {
+                            return anyhow::Ok(TokenResponse {
+                                access_token: resp.access_token().secret().to_string(),
+                                expires_in: resp.expires_in,
+                                refresh_token: Some(
+                                    resp.refresh_token()
+                                        .ok_or(anyhow::anyhow!(
+                                            "Failed to extract refresh token from auth response"
+                                        ))?
+                                        .secret()
+                                        .to_string(),
+                                ),
+                                scope: Some(resp.scope.clone()),
+                                id_token: resp.id_token().map(|t| t.secret().to_string()),
+                            });
                         }
-                        return Err(err.into());
-                    }
-                    None => {
-                        warn!("No response, token acquisition failed");
-                        return Err(anyhow::anyhow!("No response"));
+                        // Keep a code on screen until somebody signs in
+                        Err(err) if err.needs_new_code() => {
+                            warn!("{}: {err}, requesting a new device code", account.name);
+                        }
+                        Err(err) => return Err(err.into()),
                     }
                 }
-            };
+            }
+            .await;
+            SIGN_IN_PROMPTS
+                .send_modify(|prompts| prompts.retain(|prompt| prompt.account != account.name));
+            let token = result?;
+            let _refresh_guard = store.refresh_lock.lock().await;
+            store.assign(token).await;
             debug!("User authorized, token cache updated");
-            TOKEN_CACHE
-                .assign(
-                    access_token.secret().to_string(),
-                    expires_in,
-                    refresh_token.secret().to_string(),
-                )
-                .await;
-        }
-        TOKEN_CACHE.get_access_token().await
+        }
+        store.get_access_token().await
     };
-    if TOKEN_CACHE.get_access_token().await.is_empty() {
+    if access_token.is_empty() {
         return Err(anyhow::anyhow!("Failed to get access token"));
     }
-    TOKEN_CACHE.save().await?;
+    let _refresh_guard = store.refresh_lock.lock().await;
+    if let Err(e) = store.save().await {
+        warn!("Failed to save the token cache, the sign-in only lasts until exit: {e}");
+    }
     Ok(access_token)
 }
 
-async fn refresh_token(app_id: String) -> anyhow::Result<String> {
-    debug!("Refreshing token");
-    let access_token = {
-        let refresh_token = TOKEN_CACHE.get_refresh_token().await;
-        let client = Arc::new(get_client());
-        debug!("Refreshing token with refresh token");
-        let resp = client
-            .post("https://login.microsoftonline.com/common/oauth2/v2.0/token")
-            .form(&[
-                ("client_id", app_id.as_str()),
-                ("scope", "openid offline_access user.read Calendars.Read"),
-                ("refresh_token", &refresh_token),
-                ("grant_type", "refresh_token"),
-            ])
-            .send()
-            .await?;
-        let body = resp.text().await?;
-        let token: serde_json::Value = serde_json::from_str(&body)?;
-        let access_token = token["access_token"]
-            .as_str()
-            .ok_or(anyhow::anyhow!("Failed to get access token"))?;
-        let expires_in = token["expires_in"]
-            .as_u64()
-            .ok_or(anyhow::anyhow!("Failed to get expiration"))?;
-        debug!("Token refreshed, updating cache");
-        TOKEN_CACHE
-            .assign(access_token.to_string(), expires_in, refresh_token)
-            .await;
-        TOKEN_CACHE.get_access_token().await
-    };
-    TOKEN_CACHE.save().await?;
+/// Poll until the user signs in, or the code is declined or expired
+async fn wait_for_authorization(
+    phase1: &DeviceCodePhaseOneResponse<'_>,
+) -> Result<DeviceCodeAuthorization, DeviceCodeError> {
+    let mut stream = phase1.stream();
+    while let Some(result) = stream.next().await {
+        match result {
+            Ok(resp) => return Ok(resp),
+            Err(DeviceCodeError::SlowDown) => debug!("Polling too fast, slowing down"),
+            Err(err) if err.is_pending() => debug!("Authorization pending"),
+            Err(err) => return Err(err),
+        }
+    }
+    Err(DeviceCodeError::ExpiredToken)
+}
+
+/// Error response of the token endpoint
+#[derive(Debug, Clone, Deserialize)]
+pub struct TokenErrorResponse {
+    pub error: String,
+    #[serde(default)]
+    pub error_description: String,
+}
+
+/// Why refreshing the access token failed
+#[derive(Debug)]
+pub enum RefreshError {
+    /// The refresh token is expired, revoked or missing, the user has to sign in again
+    InvalidGrant(TokenErrorResponse),
+    /// Any other error returned by the token endpoint, e.g. `temporarily_unavailable`
+    Server(TokenErrorResponse),
+    /// The token endpoint is unreachable or returned something unexpected
+    Transport(anyhow::Error),
+}
+
+impl RefreshError {
+    /// Whether signing in again is the only way out, other errors may go away by retrying
+    pub fn needs_sign_in(&self) -> bool {
+        matches!(self, RefreshError::InvalidGrant(_))
+    }
+}
+
+impl std::error::Error for RefreshError {}
+
+impl fmt::Display for RefreshError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RefreshError::InvalidGrant(e) | RefreshError::Server(e) => {
+                write!(f, "{}. {}", e.error, e.error_description)
+            }
+            RefreshError::Transport(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl From<anyhow::Error> for RefreshError {
+    fn from(e: anyhow::Error) -> Self {
+        RefreshError::Transport(e)
+    }
+}
+
+impl From<reqwest::Error> for RefreshError {
+    fn from(e: reqwest::Error) -> Self {
+        RefreshError::Transport(e.into())
+    }
+}
+
+impl From<serde_json::Error> for RefreshError {
+    fn from(e: serde_json::Error) -> Self {
+        RefreshError::Transport(e.into())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: u64,
+    /// Microsoft rotates the refresh token, the old one stops working eventually
+    refresh_token: Option<String>,
+    scope: Option<String>,
+    id_token: Option<String>,
+}
+
+/// Redeem a refresh token at the token endpoint `url`
+async fn request_token(
+    client: &reqwest::Client,
+    url: &str,
+    app_id: &str,
+    scope: &str,
+    refresh_token: &str,
+) -> Result<TokenResponse, RefreshError> {
+    if refresh_token.is_empty() {
+        return Err(RefreshError::InvalidGrant(TokenErrorResponse {
+            error: "invalid_grant".to_string(),
+            error_description: "No refresh token".to_string(),
+        }));
+    }
+    let resp = client
+        .post(url)
+        .form(&[
+            ("client_id", app_id),
+            ("scope", scope),
+            ("refresh_token", refresh_token),
+            ("grant_type", "refresh_token"),
+        ])
+        .send()
+        .await?;
+    let status = resp.status();
+    let body = resp.text().await?;
+    if status.is_success() {
+        return Ok(serde_json::from_str(&body)?);
+    }
+    let error: TokenErrorResponse = serde_json::from_str(&body)
+        .map_err(|_| anyhow::anyhow!("Token endpoint returned {status}: {body}"))?;
+    // `interaction_required` means MFA or a new consent, which needs the user as well
+    match error.error.as_str() {
+        "invalid_grant" | "interaction_required" => Err(RefreshError::InvalidGrant(error)),
+        _ => Err(RefreshError::Server(error)),
+    }
+}
+
+async fn refresh_token(account: &Account) -> Result<String, RefreshError> {
+    let store = &account.store;
+    // The background refresh and the todo update may refresh at the same time
+    let _guard = store.refresh_lock.lock().await;
+    if !store.expires_within(REFRESH_MARGIN).await {
+        debug!("Token was refreshed by another task");
+        return Ok(store.get_access_token().await);
+    }
+    debug!("Refreshing token of {} with refresh token", account.name);
+    let refresh_token = store.get_refresh_token().await;
+    let url = format!("{}/{}/oauth2/v2.0/token", account.login_url, account.tenant);
+    let scope = account.scopes.join(" ");
+    let token = request_token(&get_client(), &url, &account.app_id, &scope, &refresh_token).await?;
+    if token.refresh_token.is_none() {
+        warn!("No new refresh token returned, keeping the old one");
+    }
+    debug!("Token refreshed, updating cache");
+    let access_token = token.access_token.clone();
+    store.assign(token).await;
+    if let Err(e) = store.save().await {
+        warn!("Failed to save the refreshed token, keeping it in memory: {e}");
+    }
     Ok(access_token)
 }
 
-pub async fn get_token(app_id: String) -> anyhow::Result<String> {
-    debug!("Getting token for app id {app_id}");
-    if TOKEN_CACHE.get_access_token().await.is_empty() {
-        debug!("Token cache is empty");
-        match TOKEN_CACHE.load().await {
-            Ok(_) => {
-                if TOKEN_CACHE.is_expired().await {
-                    debug!("Token cache is expired");
-                    if let Ok(token) = refresh_token(app_id.clone()).await {
-                        debug!("Token refreshed");
-                        Ok(token)
+/// Refresh the token, sign in with the device code only when the refresh token is dead
+async fn refresh_or_sign_in(account: &Account) -> anyhow::Result<String> {
+    match refresh_token(account).await {
+        Ok(token) => {
+            debug!("Token refreshed");
+            Ok(token)
+        }
+        Err(e) if e.needs_sign_in() => {
+            warn!("Refresh token is no longer valid, signing in again: {e}");
+            account.store.reset(&account.app_id, &account.tenant).await;
+            do_get_token(account).await
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// What `auth status` shows about a cached sign-in
+#[derive(Debug)]
+pub struct AuthStatus {
+    pub account: String,
+    pub cache_path: PathBuf,
+    pub tenant: String,
+    pub app_id: String,
+    pub scopes: Vec<String>,
+    pub expires_on: chrono::DateTime<chrono::Utc>,
+    pub user: Option<String>,
+    pub tenant_id: Option<String>,
+    /// The refresh token is kept, so the access token can be renewed without signing in
+    pub refreshable: bool,
+}
+
+/// The claims of the ID token we show, the token comes from the login endpoint over TLS
+#[derive(Debug, Deserialize)]
+struct IdTokenClaims {
+    name: Option<String>,
+    preferred_username: Option<String>,
+    tid: Option<String>,
+}
+
+fn decode_id_token(id_token: &str) -> anyhow::Result<IdTokenClaims> {
+    let token = jwt_compact::UntrustedToken::new(id_token)?;
+    Ok(token
+        .deserialize_claims_unchecked::<IdTokenClaims>()?
+        .custom)
+}
+
+impl Account {
+    /// Sign in with the device code even if there is a valid token
+    pub async fn login(&self) -> anyhow::Result<()> {
+        self.store.reset(&self.app_id, &self.tenant).await;
+        do_get_token(self).await?;
+        Ok(())
+    }
+
+    /// Read the saved token cache, `None` if not signed in
+    pub async fn status(&self) -> anyhow::Result<Option<AuthStatus>> {
+        if !self.store.path.exists() {
+            return Ok(None);
+        }
+        self.store.load().await?;
+        let cache = self.store.cache.lock().await.clone();
+        let claims = match cache.id_token.as_deref().map(decode_id_token) {
+            Some(Ok(claims)) => Some(claims),
+            Some(Err(e)) => {
+                warn!("Failed to decode ID token: {e}");
+                None
+            }
+            None => None,
+        };
+        let (user, tenant_id) = claims
+            .map(|c| {
+                let user = match (c.name, c.preferred_username) {
+                    (Some(name), Some(username)) => Some(format!("{name} <{username}>")),
+                    (name, username) => name.or(username),
+                };
+                (user, c.tid)
+            })
+            .unwrap_or_default();
+        Ok(Some(AuthStatus {
+            account: self.name.clone(),
+            cache_path: self.store.path.clone(),
+            tenant: cache.tenant,
+            app_id: cache.app_id,
+            scopes: cache.scope.split_whitespace().map(str::to_string).collect(),
+            expires_on: cache.expires_on,
+            user,
+            tenant_id,
+            refreshable: !cache.refresh_token.is_empty(),
+        }))
+    }
+
+    /// Delete the token cache, the next `get_token` signs in again
+    pub async fn logout(&self) -> anyhow::Result<bool> {
+        self.store.reset(&self.app_id, &self.tenant).await;
+        match std::fs::remove_file(&self.store.path) {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub async fn get_token(&self) -> anyhow::Result<String> {
+        let (app_id, tenant, store) = (&self.app_id, &self.tenant, &self.store);
+        debug!(
+            "Getting token of {} for app id {app_id} in tenant {tenant}",
+            self.name
+        );
+        if !store.get_access_token().await.is_empty() && !store.matches(app_id, tenant).await {
+            warn!("Token cache is for another tenant or app, signing in again");
+            store.reset(app_id, tenant).await;
+            return do_get_token(self).await;
+        }
+        if store.get_access_token().await.is_empty() {
+            debug!("Token cache is empty");
+            match store.load().await {
+                Ok(_) if !store.matches(app_id, tenant).await => {
+                    warn!("Saved token is for another tenant or app, signing in again");
+                    store.reset(app_id, tenant).await;
+                    do_get_token(self).await
+                }
+                Ok(_) => {
+                    if store.is_expired().await {
+                        debug!("Token cache is expired");
+                        refresh_or_sign_in(self).await
                     } else {
-                        do_get_token(app_id.clone()).await
+                        debug!("Token cache is valid until {}", store.expire_on().await);
+                        Ok(store.get_access_token().await)
                     }
-                } else {
-                    debug!(
-                        "Token cache is valid until {}",
-                        TOKEN_CACHE.expire_on().await
-                    );
-                    Ok(TOKEN_CACHE.get_access_token().await)
+                }
+                Err(err) => {
+                    warn!("Failed to load token cache: {err}");
+                    store.reset(app_id, tenant).await;
+                    do_get_token(self).await
                 }
             }
-            Err(err) => {
-                warn!("Failed to load token cache: {err}");
-                do_get_token(app_id).await
+        } else {
+            debug!("Token cache is not empty");
+            if store.is_expired().await {
+                debug!("Token cache is expired");
+                refresh_or_sign_in(self).await
+            } else {
+                debug!("Token cache is valid");
+                Ok(store.get_access_token().await)
             }
         }
-    } else {
-        debug!("Token cache is not empty");
-        if TOKEN_CACHE.is_expired().await {
-            debug!("Token cache is expired");
-            refresh_token(app_id).await
-        } else {
-            debug!("Token cache is valid");
-            Ok(TOKEN_CACHE.get_access_token().await)
+    }
+
+    /// Refresh the token shortly before it expires, so the refresh token keeps rotating even
+    /// if nothing asks for the token for a while
+    pub async fn keep_token_fresh(&self) {
+        let store = &self.store;
+        let mut retry_interval = RETRY_INTERVAL;
+        loop {
+            let wait = if store.get_refresh_token().await.is_empty()
+                || !store.matches(&self.app_id, &self.tenant).await
+            {
+                // Not signed in yet, `get_token` takes care of that
+                RETRY_INTERVAL
+            } else if store.expires_within(REFRESH_MARGIN).await {
+                match refresh_token(self).await {
+                    Ok(_) => {
+                        info!(
+                            "Token of {} refreshed in background, valid until {}",
+                            self.name,
+                            store.expire_on().await
+                        );
+                        retry_interval = RETRY_INTERVAL;
+                        continue;
+                    }
+                    Err(e) if e.needs_sign_in() => {
+                        // Retrying a dead refresh token never helps, wait for the next sign-in
+                        warn!(
+                            "Refresh token of {} is no longer valid, stopping background refresh until signed in again: {e}",
+                            self.name
+                        );
+                        store.reset(&self.app_id, &self.tenant).await;
+                        RETRY_INTERVAL
+                    }
+                    Err(e) => {
+                        warn!(
+                            "Failed to refresh token in background, retrying in {retry_interval:?}, error: {e}"
+                        );
+                        let wait = retry_interval;
+                        retry_interval = (retry_interval * 2).min(MAX_RETRY_INTERVAL);
+                        wait
+                    }
+                }
+            } else {
+                (store.expire_on().await - REFRESH_MARGIN - chrono::Utc::now())
+                    .to_std()
+                    .unwrap_or_default()
+                    .max(RETRY_INTERVAL)
+            };
+            debug!("Next background token refresh check in {wait:?}");
+            tokio::time::sleep(wait).await;
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use wiremock::matchers::{body_string_contains, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use super::*;
+
+    #[test]
+    fn test_cache_tenant() {
+        // A cache saved before the tenant was recorded
+        let cache: TokenCache = serde_json::from_str(
+            r#"{"access_token":"a","expires_on":"2024-06-03T00:00:00Z","refresh_token":"r"}"#,
+        )
+        .unwrap();
+        assert!(cache.matches("app", "consumers"));
+        assert!(!cache.matches("app", "organizations"));
+        let cache = TokenCache {
+            tenant: "organizations".to_string(),
+            app_id: "app".to_string(),
+            ..cache
+        };
+        assert!(cache.matches("app", "organizations"));
+        assert!(!cache.matches("other-app", "organizations"));
+        assert!(!cache.matches("app", "common"));
+    }
+
+    async fn mock_token_endpoint(status: u16, body: serde_json::Value) -> MockServer {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/consumers/oauth2/v2.0/token"))
+            .and(body_string_contains("grant_type=refresh_token"))
+            .respond_with(ResponseTemplate::new(status).set_body_json(body))
+            .mount(&server)
+            .await;
+        server
+    }
+
+    /// An account signed in with `refresh`, its access token about to expire
+    async fn signed_in_account(server: &MockServer, test: &str) -> Account {
+        let cache =
+            std::env::temp_dir().join(format!("todo-station-{test}-{}.json", std::process::id()));
+        let account = Account::new(
+            test,
+            &server.uri(),
+            "app",
+            "consumers",
+            &cache,
+            &KeySource::Passphrase("test".to_string()),
+            &[],
+        );
+        account.store.reset("app", "consumers").await;
+        account
+            .store
+            .assign(TokenResponse {
+                access_token: "access".to_string(),
+                expires_in: 10,
+                refresh_token: Some("refresh".to_string()),
+                scope: None,
+                id_token: None,
+            })
+            .await;
+        account
+    }
+
+    #[tokio::test]
+    async fn test_background_refresh_stops_on_invalid_grant() {
+        let server = mock_token_endpoint(400, json!({ "error": "invalid_grant" })).await;
+        let account = signed_in_account(&server, "background-invalid-grant").await;
+        let _ = tokio::time::timeout(Duration::from_secs(1), account.keep_token_fresh()).await;
+        assert_eq!(server.received_requests().await.unwrap().len(), 1);
+        // The dead refresh token is dropped, so it's not retried until a new sign-in
+        assert!(account.store.get_refresh_token().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_background_refresh_keeps_token_on_server_error() {
+        let server = mock_token_endpoint(503, json!({ "error": "temporarily_unavailable" })).await;
+        let account = signed_in_account(&server, "background-server-error").await;
+        let _ = tokio::time::timeout(Duration::from_secs(1), account.keep_token_fresh()).await;
+        assert_eq!(server.received_requests().await.unwrap().len(), 1);
+        assert_eq!(account.store.get_refresh_token().await, "refresh");
+    }
+
+    #[tokio::test]
+    async fn test_refresh_without_key() {
+        let server = mock_token_endpoint(
+            200,
+            json!({ "access_token": "new", "expires_in": 3600, "refresh_token": "refresh2" }),
+        )
+        .await;
+        let cache =
+            std::env::temp_dir().join(format!("todo-station-no-key-{}.json", std::process::id()));
+        let account = Account::new(
+            "no-key",
+            &server.uri(),
+            "app",
+            "consumers",
+            &cache,
+            &KeySource::File(cache.with_extension("missing")),
+            &[],
+        );
+        account.store.reset("app", "consumers").await;
+        account
+            .store
+            .assign(TokenResponse {
+                access_token: "access".to_string(),
+                expires_in: 10,
+                refresh_token: Some("refresh".to_string()),
+                scope: None,
+                id_token: None,
+            })
+            .await;
+        // The cache can't be encrypted, the refreshed token is still used
+        assert_eq!(refresh_token(&account).await.unwrap(), "new");
+        assert_eq!(account.store.get_refresh_token().await, "refresh2");
+        assert!(!cache.exists());
+    }
+
+    #[tokio::test]
+    async fn test_sign_in_does_not_block_refresh() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/consumers/oauth2/v2.0/devicecode"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "device_code": "code",
+                "user_code": "CODE",
+                "verification_uri": "https://microsoft.com/devicelogin",
+                "expires_in": 900,
+                "interval": 0,
+                "message": "Enter CODE to sign in",
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/consumers/oauth2/v2.0/token"))
+            .respond_with(
+                ResponseTemplate::new(400)
+                    .set_body_json(json!({ "error": "authorization_pending" })),
+            )
+            .mount(&server)
+            .await;
+        let account = signed_in_account(&server, "sign-in-lock").await;
+        let login = tokio::spawn({
+            let account = account.clone();
+            async move { account.login().await }
+        });
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(!login.is_finished());
+        assert!(account.store.sign_in_lock.try_lock().is_err());
+        assert!(account.store.refresh_lock.try_lock().is_ok());
+        login.abort();
+    }
+
+    #[tokio::test]
+    async fn test_rotated_refresh_token() {
+        let server = mock_token_endpoint(
+            200,
+            json!({
+                "token_type": "Bearer",
+                "access_token": "new-access",
+                "expires_in": 3600,
+                "refresh_token": "new-refresh",
+            }),
+        )
+        .await;
+        let url = format!("{}/consumers/oauth2/v2.0/token", server.uri());
+        let token = request_token(&get_client(), &url, "app", "openid", "old-refresh")
+            .await
+            .unwrap();
+        assert_eq!(token.access_token, "new-access");
+        assert_eq!(token.refresh_token.as_deref(), Some("new-refresh"));
+    }
+
+    #[tokio::test]
+    async fn test_refresh_errors() {
+        let server = mock_token_endpoint(
+            400,
+            json!({
+                "error": "invalid_grant",
+                "error_description": "AADSTS700082: The refresh token has expired",
+            }),
+        )
+        .await;
+        let url = format!("{}/consumers/oauth2/v2.0/token", server.uri());
+        let err = request_token(&get_client(), &url, "app", "openid", "old-refresh")
+            .await
+            .unwrap_err();
+        assert!(matches!(err, RefreshError::InvalidGrant(_)));
+        assert!(err.needs_sign_in());
+
+        let server = mock_token_endpoint(503, json!({ "error": "temporarily_unavailable" })).await;
+        let url = format!("{}/consumers/oauth2/v2.0/token", server.uri());
+        let err = request_token(&get_client(), &url, "app", "openid", "old-refresh")
+            .await
+            .unwrap_err();
+        assert!(matches!(err, RefreshError::Server(_)));
+        assert!(!err.needs_sign_in());
+
+        let err = request_token(&get_client(), &url, "app", "openid", "")
+            .await
+            .unwrap_err();
+        assert!(err.needs_sign_in());
+    }
+
+    #[tokio::test]
+    async fn test_auth_status() {
+        // {"alg":"RS256","typ":"JWT"}.{"name":"Alice","preferred_username":"alice@contoso.com",
+        // "tid":"9188040d-6c67-4c5b-b112-36a304b66dad"}, the signature isn't checked
+        let id_token = concat!(
+            "eyJhbGciOiJSUzI1NiIsInR5cCI6IkpXVCJ9.eyJuYW1lIjoiQWxpY2UiLCJ",
+            "wcmVmZXJyZWRfdXNlcm5hbWUiOiJhbGljZUBjb250b3NvLmNvbSIsInRpZCI",
+            "6IjkxODgwNDBkLTZjNjctNGM1Yi1iMTEyLTM2YTMwNGI2NmRhZCJ9.c2ln",
+        )
+        .to_string();
+        let key = KeySource::Passphrase("test".to_string());
+        let path = std::env::temp_dir().join(format!("todo-station-{}.json", std::process::id()));
+        let account = Account::new(
+            "Outlook",
+            "http://localhost",
+            "app",
+            "consumers",
+            &path,
+            &key,
+            &[],
+        );
+        assert!(account.status().await.unwrap().is_none());
+
+        account.store.reset("app", "consumers").await;
+        account
+            .store
+            .assign(TokenResponse {
+                access_token: "access".to_string(),
+                expires_in: 3600,
+                refresh_token: Some("refresh".to_string()),
+                scope: Some("openid Calendars.Read".to_string()),
+                id_token: Some(id_token),
+            })
+            .await;
+        // A refresh without the optional fields keeps the old ones
+        account
+            .store
+            .assign(TokenResponse {
+                access_token: "new-access".to_string(),
+                expires_in: 3600,
+                refresh_token: None,
+                scope: None,
+                id_token: None,
+            })
+            .await;
+        account.store.save().await.unwrap();
+
+        let status = account.status().await.unwrap().unwrap();
+        assert_eq!(status.user.as_deref(), Some("Alice <alice@contoso.com>"));
+        assert_eq!(
+            status.tenant_id.as_deref(),
+            Some("9188040d-6c67-4c5b-b112-36a304b66dad")
+        );
+        assert_eq!(status.tenant, "consumers");
+        assert_eq!(status.scopes, ["openid", "Calendars.Read"]);
+        assert!(status.refreshable);
+        assert!(status.expires_on > chrono::Utc::now());
+
+        assert!(account.logout().await.unwrap());
+        assert!(!path.exists());
+        assert!(account.store.get_access_token().await.is_empty());
+        assert!(!account.logout().await.unwrap());
+        assert!(account.status().await.unwrap().is_none());
+    }
+
+    #[test]
+    fn test_token_stores() {
+        let state_dir = AppDirs::new(Some("todo-station"), false).unwrap().state_dir;
+        assert_eq!(
+            cache_path("Outlook", None),
+            state_dir.join("token_cache.json")
+        );
+        assert_eq!(
+            cache_path("Work / Rooms", None),
+            state_dir.join("token_cache_Work___Rooms.json")
+        );
+        assert_eq!(
+            cache_path("Work", Some(Path::new("/tmp/work.json"))),
+            PathBuf::from("/tmp/work.json")
+        );
+        // Accounts using the same cache file share the tokens
+        let key = KeySource::Passphrase("test".to_string());
+        let path = Path::new("/tmp/todo-station-test-cache.json");
+        let a = Account::new("a", "http://localhost", "app", "consumers", path, &key, &[]);
+        let b = Account::new("b", "http://localhost", "app", "consumers", path, &key, &[]);
+        let c = Account::new(
+            "c",
+            "http://localhost",
+            "app",
+            "consumers",
+            &cache_path("c", None),
+            &key,
+            &[],
+        );
+        assert!(Arc::ptr_eq(&a.store, &b.store));
+        assert!(!Arc::ptr_eq(&a.store, &c.store));
+    }
+}
@@ -0,0 +1,547 @@
+use std::fmt::Write;
+
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime};
+
+use crate::config::{Rgb, WeatherConfig, WeatherProviderKind};
+use open_meteo::OpenMeteo;
+use qweather::QWeather;
+
+mod open_meteo;
+mod qweather;
+
+/// Weather condition, independent of the provider
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Condition {
+    Clear,
+    MostlyClear,
+    PartlyCloudy,
+    Cloudy,
+    Overcast,
+    Showers,
+    HeavyShowers,
+    Thunderstorm,
+    HeavyThunderstorm,
+    Hail,
+    Drizzle,
+    LightRain,
+    Rain,
+    HeavyRain,
+    Rainstorm,
+    HeavyRainstorm,
+    SevereRainstorm,
+    ExtremeRain,
+    /// The ranges forecast by QWeather, e.g. light to moderate rain
+    LightToModerateRain,
+    ModerateToHeavyRain,
+    HeavyRainToRainstorm,
+    RainstormToHeavyRainstorm,
+    HeavyToSevereRainstorm,
+    /// Rain of unknown intensity
+    GenericRain,
+    FreezingRain,
+    Sleet,
+    RainAndSnow,
+    SleetShowers,
+    LightSnow,
+    Snow,
+    HeavySnow,
+    LightToModerateSnow,
+    ModerateToHeavySnow,
+    HeavySnowToBlizzard,
+    /// Snow of unknown intensity
+    GenericSnow,
+    Blizzard,
+    SnowShowers,
+    Mist,
+    Fog,
+    DenseFog,
+    StrongDenseFog,
+    HeavyFog,
+    ExtremeDenseFog,
+    Haze,
+    ModerateHaze,
+    HeavyHaze,
+    SevereHaze,
+    Dust,
+    BlowingSand,
+    Sandstorm,
+    SevereSandstorm,
+    Hot,
+    Cold,
+    #[default]
+    Unknown,
+}
+
+impl Condition {
+    /// Name of the icon in `ui/assets`, the icons are the QWeather ones
+    pub fn icon(self, is_day: bool) -> &'static str {
+        use Condition::*;
+        match (self, is_day) {
+            (Clear, true) => "100",
+            (Clear, false) => "150",
+            (MostlyClear, true) => "102",
+            (MostlyClear, false) => "152",
+            (PartlyCloudy, true) => "103",
+            (PartlyCloudy, false) => "153",
+            (Cloudy, true) => "101",
+            (Cloudy, false) => "151",
+            (Overcast, _) => "104",
+            (Showers, true) => "300",
+            (Showers, false) => "350",
+            (HeavyShowers, true) => "301",
+            (HeavyShowers, false) => "351",
+            (Thunderstorm, _) => "302",
+            (HeavyThunderstorm, _) => "303",
+            (Hail, _) => "304",
+            (Drizzle, _) => "309",
+            (LightRain, _) => "305",
+            (Rain, _) => "306",
+            (HeavyRain, _) => "307",
+            (Rainstorm, _) => "310",
+            (HeavyRainstorm, _) => "311",
+            (SevereRainstorm, _) => "312",
+            (ExtremeRain, _) => "308",
+            (LightToModerateRain, _) => "314",
+            (ModerateToHeavyRain, _) => "315",
+            (HeavyRainToRainstorm, _) => "316",
+            (RainstormToHeavyRainstorm, _) => "317",
+            (HeavyToSevereRainstorm, _) => "318",
+            (GenericRain, _) => "399",
+            (FreezingRain, _) => "313",
+            (Sleet, _) => "404",
+            (RainAndSnow, _) => "405",
+            (SleetShowers, true) => "406",
+            (SleetShowers, false) => "456",
+            (LightSnow, _) => "400",
+            (Snow, _) => "401",
+            (HeavySnow, _) => "402",
+            (LightToModerateSnow, _) => "408",
+            (ModerateToHeavySnow, _) => "409",
+            (HeavySnowToBlizzard, _) => "410",
+            (GenericSnow, _) => "499",
+            (Blizzard, _) => "403",
+            (SnowShowers, true) => "407",
+            (SnowShowers, false) => "457",
+            (Mist, _) => "500",
+            (Fog, _) => "501",
+            (DenseFog, _) => "509",
+            (StrongDenseFog, _) => "510",
+            (HeavyFog, _) => "514",
+            (ExtremeDenseFog, _) => "515",
+            (Haze, _) => "502",
+            (ModerateHaze, _) => "511",
+            (HeavyHaze, _) => "512",
+            (SevereHaze, _) => "513",
+            (Dust, _) => "504",
+            (BlowingSand, _) => "503",
+            (Sandstorm, _) => "507",
+            (SevereSandstorm, _) => "508",
+            (Hot, _) => "900",
+            (Cold, _) => "901",
+            (Unknown, _) => "999",
+        }
+    }
+}
+
+/// Phase of the moon, starting from the new moon
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MoonPhase {
+    New,
+    WaxingCrescent,
+    FirstQuarter,
+    WaxingGibbous,
+    Full,
+    WaningGibbous,
+    LastQuarter,
+    WaningCrescent,
+}
+
+impl MoonPhase {
+    /// Name of the icon in `ui/assets`
+    pub fn icon(self) -> &'static str {
+        match self {
+            MoonPhase::New => "800",
+            MoonPhase::WaxingCrescent => "801",
+            MoonPhase::FirstQuarter => "802",
+            MoonPhase::WaxingGibbous => "803",
+            MoonPhase::Full => "804",
+            MoonPhase::WaningGibbous => "805",
+            MoonPhase::LastQuarter => "806",
+            MoonPhase::WaningCrescent => "807",
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            MoonPhase::New => "新月",
+            MoonPhase::WaxingCrescent => "蛾眉月",
+            MoonPhase::FirstQuarter => "上弦月",
+            MoonPhase::WaxingGibbous => "盈凸月",
+            MoonPhase::Full => "满月",
+            MoonPhase::WaningGibbous => "亏凸月",
+            MoonPhase::LastQuarter => "下弦月",
+            MoonPhase::WaningCrescent => "残月",
+        }
+    }
+}
+
+/// Forecast of one day
+#[derive(Debug, Clone, PartialEq)]
+pub struct DailyWeather {
+    pub date: NaiveDate,
+    pub high: f32,
+    pub low: f32,
+    pub condition_day: Condition,
+    pub condition_night: Condition,
+    /// Total precipitation in mm
+    pub precipitation: f32,
+    /// Local times of the location, `None` if it doesn't happen on the day, e.g. the polar
+    /// night, or the provider doesn't have it
+    pub sunrise: Option<NaiveTime>,
+    pub sunset: Option<NaiveTime>,
+    pub moonrise: Option<NaiveTime>,
+    pub moonset: Option<NaiveTime>,
+    pub moon_phase: Option<MoonPhase>,
+}
+
+impl DailyWeather {
+    pub fn icon_day(&self) -> &'static str {
+        self.condition_day.icon(true)
+    }
+
+    pub fn icon_night(&self) -> &'static str {
+        self.condition_night.icon(false)
+    }
+}
+
+/// Forecast of one hour
+#[derive(Debug, Clone, PartialEq)]
+pub struct HourlyWeather {
+    /// Local time of the location
+    pub time: NaiveDateTime,
+    pub temperature: f32,
+    /// mm in the hour
+    pub precipitation: f32,
+}
+
+/// Precipitation nowcast of a few minutes
+#[derive(Debug, Clone, PartialEq)]
+pub struct Precipitation {
+    /// Local time of the location
+    pub time: NaiveDateTime,
+    /// mm/h, the providers use different intervals
+    pub intensity: f32,
+}
+
+/// Severity of a warning, ordered from the least severe
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    #[default]
+    Unknown,
+    Minor,
+    Moderate,
+    Severe,
+    Extreme,
+}
+
+/// An active severe weather warning
+#[derive(Debug, Clone, PartialEq)]
+pub struct WeatherWarning {
+    pub severity: Severity,
+    /// The color the issuer gave, e.g. typhoon blue to red in China
+    pub color: Option<Rgb>,
+    /// Type of the warning, e.g. "台风" or "暴雨"
+    pub type_name: String,
+    pub title: String,
+    pub text: String,
+}
+
+impl WeatherWarning {
+    /// The given color, or one by the severity if the issuer didn't give one
+    pub fn color(&self) -> Rgb {
+        self.color.unwrap_or(match self.severity {
+            Severity::Extreme => [0xe5, 0x39, 0x35],
+            Severity::Severe => [0xfb, 0x8c, 0x00],
+            Severity::Moderate => [0xfd, 0xd8, 0x35],
+            Severity::Minor => [0x1e, 0x88, 0xe5],
+            Severity::Unknown => [0x75, 0x75, 0x75],
+        })
+    }
+
+    /// Whether dark text reads better on the color
+    pub fn dark_text(&self) -> bool {
+        let [r, g, b] = self.color();
+        // Rec. 601 luma of the gamma-encoded values, good enough for picking the text color
+        0.299 * r as f32 + 0.587 * g as f32 + 0.114 * b as f32 > 150.0
+    }
+}
+
+/// Air quality by the Chinese AQI standard
+#[derive(Debug, Clone, PartialEq)]
+pub struct AirQuality {
+    pub aqi: u32,
+    /// 1 to 6, from 优 to 严重
+    pub level: u8,
+    /// e.g. "良" or "轻度污染"
+    pub category: String,
+    /// Display name like "PM2.5", `None` when the air is good
+    pub primary_pollutant: Option<String>,
+}
+
+impl AirQuality {
+    /// The standard color of the level
+    pub fn color(&self) -> Rgb {
+        match self.level {
+            1 => [0x00, 0xe4, 0x00],
+            2 => [0xff, 0xff, 0x00],
+            3 => [0xff, 0x7e, 0x00],
+            4 => [0xff, 0x00, 0x00],
+            5 => [0x99, 0x00, 0x4c],
+            _ => [0x7e, 0x00, 0x23],
+        }
+    }
+
+    /// Whether dark text reads better on the color
+    pub fn dark_text(&self) -> bool {
+        self.level <= 3
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Weather {
+    pub temperature: f32,
+    pub high: f32,
+    pub low: f32,
+    pub condition: Condition,
+    /// Some conditions have a different icon at night
+    pub is_day: bool,
+    /// Starts from today
+    pub forecast: Vec<DailyWeather>,
+    /// Starts from the current hour
+    pub hourly: Vec<HourlyWeather>,
+    /// The next two hours, empty if the provider has no nowcast for the location
+    pub minutely: Vec<Precipitation>,
+    /// Text summary of the nowcast, e.g. "未来两小时无降水"
+    pub precipitation_summary: String,
+    /// Active warnings, the most severe first
+    pub warnings: Vec<WeatherWarning>,
+    /// `None` if disabled in the config or not available
+    pub air_quality: Option<AirQuality>,
+}
+
+impl Weather {
+    pub fn icon(&self) -> &'static str {
+        self.condition.icon(self.is_day)
+    }
+
+    /// The forecast of today, with the sun and moon times
+    pub fn today(&self) -> Option<&DailyWeather> {
+        self.forecast.first()
+    }
+}
+
+/// A weather service the current weather and the forecast come from
+pub trait WeatherProvider {
+    async fn get_weather(&self) -> anyhow::Result<Weather>;
+}
+
+/// All supported weather providers
+#[derive(Debug, Clone)]
+pub enum Provider {
+    QWeather(QWeather),
+    OpenMeteo(OpenMeteo),
+}
+
+impl WeatherProvider for Provider {
+    async fn get_weather(&self) -> anyhow::Result<Weather> {
+        match self {
+            Provider::QWeather(provider) => provider.get_weather().await,
+            Provider::OpenMeteo(provider) => provider.get_weather().await,
+        }
+    }
+}
+
+/// Create the weather provider in the config
+pub fn get_provider(cfg: &WeatherConfig) -> anyhow::Result<Provider> {
+    Ok(match cfg.provider {
+        WeatherProviderKind::QWeather => Provider::QWeather(QWeather::new(cfg)),
+        WeatherProviderKind::OpenMeteo => Provider::OpenMeteo(OpenMeteo::new(cfg)?),
+    })
+}
+
+/// SVG path commands of the temperature line in a 1000x100 viewbox, one point in the middle
+/// of each hour, the lowest temperature at the bottom
+pub fn temperature_path(hourly: &[HourlyWeather]) -> String {
+    let min = hourly
+        .iter()
+        .map(|h| h.temperature)
+        .fold(f32::MAX, f32::min);
+    let max = hourly
+        .iter()
+        .map(|h| h.temperature)
+        .fold(f32::MIN, f32::max);
+    let mut path = String::new();
+    for (i, hour) in hourly.iter().enumerate() {
+        let x = (i as f32 + 0.5) * 1000.0 / hourly.len() as f32;
+        // Keep some room for the stroke, a flat line stays in the middle
+        let y = if max > min {
+            90.0 - (hour.temperature - min) / (max - min) * 80.0
+        } else {
+            50.0
+        };
+        let command = if i == 0 { 'M' } else { 'L' };
+        let _ = write!(path, "{command} {x:.1} {y:.1} ");
+    }
+    path.trim_end().to_string()
+}
+
+/// The API host in the config may come with or without the scheme
+fn base_url(api_host: &str) -> String {
+    let api_host = api_host.trim_end_matches('/');
+    if api_host.contains("://") {
+        api_host.to_string()
+    } else {
+        format!("https://{api_host}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_icons() {
+        use Condition::*;
+        let conditions = [
+            Clear,
+            MostlyClear,
+            PartlyCloudy,
+            Cloudy,
+            Overcast,
+            Showers,
+            HeavyShowers,
+            Thunderstorm,
+            HeavyThunderstorm,
+            Hail,
+            Drizzle,
+            LightRain,
+            Rain,
+            HeavyRain,
+            Rainstorm,
+            HeavyRainstorm,
+            SevereRainstorm,
+            ExtremeRain,
+            LightToModerateRain,
+            ModerateToHeavyRain,
+            HeavyRainToRainstorm,
+            RainstormToHeavyRainstorm,
+            HeavyToSevereRainstorm,
+            GenericRain,
+            FreezingRain,
+            Sleet,
+            RainAndSnow,
+            SleetShowers,
+            LightSnow,
+            Snow,
+            HeavySnow,
+            LightToModerateSnow,
+            ModerateToHeavySnow,
+            HeavySnowToBlizzard,
+            GenericSnow,
+            Blizzard,
+            SnowShowers,
+            Mist,
+            Fog,
+            DenseFog,
+            StrongDenseFog,
+            HeavyFog,
+            ExtremeDenseFog,
+            Haze,
+            ModerateHaze,
+            HeavyHaze,
+            SevereHaze,
+            Dust,
+            BlowingSand,
+            Sandstorm,
+            SevereSandstorm,
+            Hot,
+            Cold,
+            Unknown,
+        ];
+        let assets = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("ui/assets");
+        for condition in conditions {
+            for is_day in [true, false] {
+                let icon = format!("{}.svg", condition.icon(is_day));
+                assert!(assets.join(&icon).exists(), "{condition:?} has no {icon}");
+            }
+        }
+        assert_eq!(Clear.icon(false), "150");
+    }
+
+    #[test]
+    fn test_temperature_path() {
+        let hour = |hour: u32, temperature: f32| HourlyWeather {
+            time: NaiveDate::from_ymd_opt(2024, 6, 3)
+                .unwrap()
+                .and_hms_opt(hour, 0, 0)
+                .unwrap(),
+            temperature,
+            precipitation: 0.0,
+        };
+        assert_eq!(temperature_path(&[]), "");
+        assert_eq!(
+            temperature_path(&[
+                hour(18, 20.0),
+                hour(19, 24.0),
+                hour(20, 22.0),
+                hour(21, 20.0)
+            ]),
+            "M 125.0 90.0 L 375.0 10.0 L 625.0 50.0 L 875.0 90.0"
+        );
+        assert_eq!(
+            temperature_path(&[hour(18, 20.0), hour(19, 20.0)]),
+            "M 250.0 50.0 L 750.0 50.0"
+        );
+    }
+
+    #[test]
+    fn test_warning_color() {
+        let warning = |severity, color| WeatherWarning {
+            severity,
+            color,
+            type_name: "台风".to_string(),
+            title: "台风预警".to_string(),
+            text: String::new(),
+        };
+        assert_eq!(warning(Severity::Extreme, None).color(), [0xe5, 0x39, 0x35]);
+        assert_eq!(
+            warning(Severity::Extreme, Some([0, 0, 255])).color(),
+            [0, 0, 255]
+        );
+        assert!(warning(Severity::Moderate, None).dark_text());
+        assert!(!warning(Severity::Extreme, None).dark_text());
+        assert!(Severity::Extreme > Severity::Severe && Severity::Minor > Severity::Unknown);
+    }
+
+    #[test]
+    fn test_moon_phase_icons() {
+        let assets = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("ui/assets");
+        for phase in [MoonPhase::New, MoonPhase::Full, MoonPhase::WaningCrescent] {
+            assert!(assets.join(format!("{}.svg", phase.icon())).exists());
+        }
+        assert_eq!(MoonPhase::FirstQuarter.name(), "上弦月");
+    }
+
+    #[test]
+    fn test_base_url() {
+        assert_eq!(
+            base_url("devapi.qweather.com"),
+            "https://devapi.qweather.com"
+        );
+        assert_eq!(
+            base_url("https://abc.qweatherapi.com/"),
+            "https://abc.qweatherapi.com"
+        );
+        assert_eq!(base_url("http://127.0.0.1:8080"), "http://127.0.0.1:8080");
+    }
+}
@@ -0,0 +1,324 @@
+use chrono::{NaiveDate, NaiveDateTime};
+use log::info;
+use serde::Deserialize;
+
+use super::{
+    base_url, Condition, DailyWeather, HourlyWeather, Precipitation, Weather, WeatherProvider,
+};
+use crate::config::{get_client, WeatherConfig};
+
+const DEFAULT_API_HOST: &str = "api.open-meteo.com";
+
+#[derive(Debug, Deserialize)]
+struct Current {
+    temperature_2m: f32,
+    weather_code: u8,
+    is_day: u8,
+}
+
+#[derive(Debug, Deserialize)]
+struct Daily {
+    time: Vec<NaiveDate>,
+    weather_code: Vec<u8>,
+    temperature_2m_max: Vec<f32>,
+    temperature_2m_min: Vec<f32>,
+    precipitation_sum: Vec<f32>,
+    sunrise: Vec<String>,
+    sunset: Vec<String>,
+}
+
+impl Daily {
+    fn forecast(&self) -> Vec<DailyWeather> {
+        (0..self.time.len())
+            .filter_map(|i| {
+                // WMO gives one code for the whole day, it's used for both icons
+                let condition = condition(*self.weather_code.get(i)?);
+                let time = |times: &[String]| Some(parse_time(times.get(i)?).ok()?.time());
+                Some(DailyWeather {
+                    date: self.time[i],
+                    high: *self.temperature_2m_max.get(i)?,
+                    low: *self.temperature_2m_min.get(i)?,
+                    condition_day: condition,
+                    condition_night: condition,
+                    precipitation: *self.precipitation_sum.get(i)?,
+                    sunrise: time(&self.sunrise),
+                    sunset: time(&self.sunset),
+                    // Open-Meteo has no moon
+                    moonrise: None,
+                    moonset: None,
+                    moon_phase: None,
+                })
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Hourly {
+    time: Vec<String>,
+    temperature_2m: Vec<f32>,
+    precipitation: Vec<f32>,
+}
+
+impl Hourly {
+    fn forecast(&self) -> anyhow::Result<Vec<HourlyWeather>> {
+        self.time
+            .iter()
+            .zip(&self.temperature_2m)
+            .zip(&self.precipitation)
+            .map(|((time, temperature), precipitation)| {
+                Ok(HourlyWeather {
+                    time: parse_time(time)?,
+                    temperature: *temperature,
+                    precipitation: *precipitation,
+                })
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Minutely15 {
+    time: Vec<String>,
+    /// mm in the preceding 15 minutes
+    precipitation: Vec<Option<f32>>,
+}
+
+impl Minutely15 {
+    fn nowcast(&self) -> anyhow::Result<Vec<Precipitation>> {
+        self.time
+            .iter()
+            .zip(&self.precipitation)
+            .map(|(time, precipitation)| {
+                Ok(Precipitation {
+                    time: parse_time(time)?,
+                    intensity: precipitation.unwrap_or_default() * 4.0,
+                })
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Forecast {
+    current: Current,
+    daily: Daily,
+    hourly: Hourly,
+    minutely_15: Option<Minutely15>,
+}
+
+/// With `timezone=auto` the times are local to the location, without seconds
+fn parse_time(time: &str) -> anyhow::Result<NaiveDateTime> {
+    Ok(NaiveDateTime::parse_from_str(time, "%Y-%m-%dT%H:%M")?)
+}
+
+#[derive(Debug, Deserialize)]
+struct ErrorResponse {
+    reason: String,
+}
+
+/// Map the WMO weather interpretation code, see https://open-meteo.com/en/docs
+fn condition(code: u8) -> Condition {
+    use Condition::*;
+    match code {
+        0 => Clear,
+        1 => MostlyClear,
+        2 => PartlyCloudy,
+        3 => Overcast,
+        45 | 48 => Fog,
+        51 | 53 | 55 => Drizzle,
+        56 | 57 | 66 | 67 => FreezingRain,
+        61 => LightRain,
+        63 => Rain,
+        65 => HeavyRain,
+        71 | 77 => LightSnow,
+        73 => Snow,
+        75 => HeavySnow,
+        80 | 81 => Showers,
+        82 => HeavyShowers,
+        85 | 86 => SnowShowers,
+        95 => Thunderstorm,
+        96 | 99 => Hail,
+        _ => Unknown,
+    }
+}
+
+/// Open-Meteo, free for non-commercial use without a key, see https://open-meteo.com/
+#[derive(Debug, Clone)]
+pub struct OpenMeteo {
+    base_url: String,
+    latitude: f64,
+    longitude: f64,
+}
+
+impl OpenMeteo {
+    pub fn new(cfg: &WeatherConfig) -> anyhow::Result<Self> {
+        let (Some(latitude), Some(longitude)) = (cfg.latitude, cfg.longitude) else {
+            anyhow::bail!("Open-Meteo needs `latitude` and `longitude` in `[weather]`");
+        };
+        Ok(Self {
+            base_url: base_url(cfg.api_host.as_deref().unwrap_or(DEFAULT_API_HOST)),
+            latitude,
+            longitude,
+        })
+    }
+}
+
+impl WeatherProvider for OpenMeteo {
+    async fn get_weather(&self) -> anyhow::Result<Weather> {
+        info!("Getting weather for {},{}", self.latitude, self.longitude);
+        let resp = get_client()
+            .get(format!("{}/v1/forecast", self.base_url))
+            .query(&[
+                ("latitude", self.latitude.to_string().as_str()),
+                ("longitude", self.longitude.to_string().as_str()),
+                ("current", "temperature_2m,weather_code,is_day"),
+                (
+                    "daily",
+                    "weather_code,temperature_2m_max,temperature_2m_min,precipitation_sum,sunrise,sunset",
+                ),
+                ("hourly", "temperature_2m,precipitation"),
+                ("minutely_15", "precipitation"),
+                ("timezone", "auto"),
+                ("forecast_days", "7"),
+                ("forecast_hours", "24"),
+                // Two hours, like the QWeather nowcast
+                ("forecast_minutely_15", "8"),
+            ])
+            .send()
+            .await?;
+        if !resp.status().is_success() {
+            let status = resp.status();
+            let reason = resp
+                .json::<ErrorResponse>()
+                .await
+                .map(|error| error.reason)
+                .unwrap_or_default();
+            anyhow::bail!("Open-Meteo returned {status}: {reason}");
+        }
+        let forecast: Forecast = resp.json().await?;
+        info!("Weather retrieved");
+        let daily = forecast.daily.forecast();
+        let today = daily.first().ok_or(anyhow::anyhow!("No daily forecast"))?;
+        Ok(Weather {
+            temperature: forecast.current.temperature_2m,
+            high: today.high,
+            low: today.low,
+            condition: condition(forecast.current.weather_code),
+            is_day: forecast.current.is_day != 0,
+            forecast: daily,
+            hourly: forecast.hourly.forecast()?,
+            minutely: match &forecast.minutely_15 {
+                Some(minutely) => minutely.nowcast()?,
+                None => vec![],
+            },
+            // Open-Meteo has no text summary and no warnings
+            precipitation_summary: String::new(),
+            warnings: vec![],
+            // The air quality is a separate API with the European AQI
+            air_quality: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use wiremock::matchers::{method, path, query_param};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use super::*;
+
+    fn provider(server: &MockServer) -> OpenMeteo {
+        OpenMeteo::new(&WeatherConfig {
+            api_host: Some(server.uri()),
+            latitude: Some(52.52),
+            longitude: Some(13.41),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_get_weather() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/v1/forecast"))
+            .and(query_param("latitude", "52.52"))
+            .and(query_param("longitude", "13.41"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "latitude": 52.52,
+                "longitude": 13.419998,
+                "current": {
+                    "time": "2024-06-03T22:00",
+                    "interval": 900,
+                    "temperature_2m": 17.4,
+                    "weather_code": 0,
+                    "is_day": 0,
+                },
+                "daily": {
+                    "time": ["2024-06-03", "2024-06-04"],
+                    "weather_code": [3, 61],
+                    "temperature_2m_max": [24.1, 22.0],
+                    "temperature_2m_min": [13.2, 12.5],
+                    "precipitation_sum": [0.0, 3.4],
+                    "sunrise": ["2024-06-03T04:45", "2024-06-04T04:44"],
+                    "sunset": ["2024-06-03T21:21", "2024-06-04T21:22"],
+                },
+                "hourly": {
+                    "time": ["2024-06-03T22:00", "2024-06-03T23:00"],
+                    "temperature_2m": [17.4, 16.8],
+                    "precipitation": [0.0, 0.3],
+                },
+                "minutely_15": {
+                    "time": ["2024-06-03T22:00", "2024-06-03T22:15"],
+                    "precipitation": [0.0, 0.2],
+                },
+            })))
+            .mount(&server)
+            .await;
+        let weather = provider(&server).get_weather().await.unwrap();
+        assert_eq!(weather.temperature, 17.4);
+        assert_eq!(weather.high, 24.1);
+        assert_eq!(weather.low, 13.2);
+        assert_eq!(weather.condition, Condition::Clear);
+        assert_eq!(weather.icon(), "150");
+        assert_eq!(weather.forecast.len(), 2);
+        let tomorrow = &weather.forecast[1];
+        assert_eq!(tomorrow.date, NaiveDate::from_ymd_opt(2024, 6, 4).unwrap());
+        assert_eq!((tomorrow.high, tomorrow.low), (22.0, 12.5));
+        assert_eq!(tomorrow.condition_day, Condition::LightRain);
+        assert_eq!(tomorrow.precipitation, 3.4);
+        let today = weather.today().unwrap();
+        assert_eq!(today.sunrise, chrono::NaiveTime::from_hms_opt(4, 45, 0));
+        assert_eq!(today.sunset, chrono::NaiveTime::from_hms_opt(21, 21, 0));
+        assert_eq!(today.moon_phase, None);
+        assert_eq!(weather.hourly.len(), 2);
+        assert_eq!(weather.hourly[1].time.to_string(), "2024-06-03 23:00:00");
+        assert_eq!(weather.hourly[1].precipitation, 0.3);
+        assert_eq!(weather.minutely[1].intensity, 0.8);
+    }
+
+    #[tokio::test]
+    async fn test_error() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(400).set_body_json(json!({
+                "error": true,
+                "reason": "Latitude must be in range of -90 to 90°.",
+            })))
+            .mount(&server)
+            .await;
+        let err = provider(&server).get_weather().await.unwrap_err();
+        assert!(err.to_string().contains("Latitude must be in range"));
+    }
+
+    #[test]
+    fn test_condition() {
+        assert_eq!(condition(2), Condition::PartlyCloudy);
+        assert_eq!(condition(65), Condition::HeavyRain);
+        assert_eq!(condition(99), Condition::Hail);
+        assert_eq!(condition(42), Condition::Unknown);
+        assert!(OpenMeteo::new(&WeatherConfig::default()).is_err());
+    }
+}